@@ -0,0 +1,57 @@
+// Moving a 500-segment snake: its VecDeque body (what update() uses now)
+// against the Vec it used to be, where each move shifted the whole body over
+// with insert(0, ..). A whole update() is timed too, for scale.
+// Run with `cargo bench`.
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use snake_online::{Direction, GameState, Position, Snake, SnakeConfig, WallMode};
+
+static SNAKE_LENGTH: usize = 500;
+static TICKS: u32 = 20_000;
+// wide enough for the whole snake in a row, it wraps around forever
+static WIDTH: i32 = 1200;
+static HEIGHT: i32 = 16;
+
+// The old move_forward and check_self_collision, on a Vec body: head at index 0
+fn vec_move(body: &mut Vec<Position>) -> bool {
+    let new_head = body[0].move_in_direction(Direction::Right).wrap(WIDTH, HEIGHT);
+    body.insert(0, new_head);
+    body.pop();
+    body[1..].contains(&body[0])
+}
+
+fn time(name: &str, mut tick: impl FnMut()) {
+    let started = Instant::now();
+    for _ in 0..TICKS {
+        tick();
+    }
+    let elapsed: Duration = started.elapsed();
+    println!("{:<16} {:>10.2?} per tick", name, elapsed / TICKS);
+}
+
+fn main() {
+    let config = SnakeConfig { length: SNAKE_LENGTH, direction: Direction::Right };
+
+    let mut body: Vec<Position> = (0..SNAKE_LENGTH as i32).map(|i| Position::new(600 - i, 8)).collect();
+    time("Vec move", || {
+        black_box(vec_move(&mut body));
+    });
+
+    let mut snake = Snake::with_config(600, 8, config);
+    time("VecDeque move", || {
+        snake.move_forward(WallMode::Wrap, WIDTH, HEIGHT);
+        black_box(snake.check_self_collision());
+    });
+
+    let mut game = GameState::new_seeded(WIDTH, HEIGHT, 1)
+        .with_wall_mode(WallMode::Wrap)
+        .with_snake_config(config);
+    // out of the snake's row, so it never grows
+    game.place_food(Position::new(0, 0));
+    game.add_snake("a");
+    assert_eq!(game.snakes["a"].length(), SNAKE_LENGTH);
+    time("update()", || {
+        black_box(game.update());
+    });
+    assert!(game.snakes["a"].alive);
+}
@@ -1,19 +1,61 @@
-use std::{collections::HashSet};
+use std::{collections::{HashMap, HashSet, VecDeque}, io::{Error, ErrorKind}, ops::{Add, Deref, DerefMut, Sub}, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+use serde::{ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
+
+static BODY_SPARE_CAPACITY : usize = 16;
+static MAX_QUEUED_DIRECTIONS : usize = 3; // more than that and the controls feel laggy
+static POISON_SHRINK : usize = 2; // cells lost per poison eaten
+static POWER_UP_TICKS : u32 = 30; // how long speed and slow last
+static BONUS_POINTS : i32 = 30;
+static MAX_BINARY_BOARD_SIDE : i32 = 1024; // from_binary won't take boards bigger than this
 
 // ============================================================================
 // TIPOS BÁSICOS DO JOGO
 // ============================================================================
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct JoinGame {
     pub game_id: Option<String>,
     pub size: Option<Size>,
+    pub wall_mode: Option<WallMode>,
+    pub delta_updates: Option<bool>, // game_delta messages instead of full game_state ones
+    pub speed_ramp: Option<SpeedRamp>,
+    pub obstacles: Option<usize>, // how many wall blocks to scatter over the board
+    pub binary: Option<bool>, // game state as binary frames (GameState::to_binary) instead of json
+    pub time_limit_ms: Option<u64>, // game ends when this runs out, collisions or not
+    pub snake_length: Option<usize>,
+    pub start_direction: Option<Direction>,
+    pub poison_chance: Option<f64>, // 0.0 to 1.0, odds of each new food being poison
+    pub power_up_chance: Option<f64>, // same, for power-ups
+    pub add_bot: Option<bool>, // a computer driven snake joins too
+    pub versus: Option<bool>, // last snake standing wins and ends the game
+    pub max_players: Option<usize>, // room capacity, spectators included
+    pub summary: Option<bool>, // game_summary messages, no bodies, for the bandwidth starved
+    pub max_length: Option<usize>, // a snake this long wins the game
+    pub food_value: Option<i32>, // points per (normal) food
+    pub length_bonus: Option<bool>, // food is also worth the eater's length in points
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum WallMode {
+    #[default]
+    Solid, // leaving the board ends the game
+    Wrap,  // leaving the board re-enters from the opposite edge
+}
+
+// Arcade style: the game gets faster with every food eaten
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpeedRamp {
+    pub start_interval: u16, // milliseconds
+    pub min_interval: u16,
+    pub step: u16, // taken off the interval per food
 }
 
 
+// Goes over the wire as "Up", "Down"... but reads anything FromStr takes
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
 pub enum Direction {
     Up,
     Down,
@@ -21,13 +63,50 @@ pub enum Direction {
     Right,
 }
 
+// Also takes key names, so clients can just forward whatever key was pressed:
+// "up", "ArrowUp", "w"... in any case
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "up" | "arrowup" | "w" => Ok(Direction::Up),
+            "down" | "arrowdown" | "s" => Ok(Direction::Down),
+            "left" | "arrowleft" | "a" => Ok(Direction::Left),
+            "right" | "arrowright" | "d" => Ok(Direction::Right),
+            _ => Err(format!("'{}' is not a direction", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Direction {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Direction {
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Position {
     pub x: i32,
     pub y: i32,
 }
 
+// Either side can be left out, it takes the default one
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Size {
     pub width: i32,
     pub height: i32,
@@ -52,179 +131,1313 @@ impl Position {
             Direction::Right => Position::new(self.x + 1, self.y),
         }
     }
+
+    // Same position on a board whose edges wrap around (a torus), e.g.
+    // x = -1 becomes width - 1
+    pub fn wrap(self, width: i32, height: i32) -> Self {
+        Position::new(self.x.rem_euclid(width), self.y.rem_euclid(height))
+    }
+}
+
+impl Add for Position {
+    type Output = Position;
+
+    fn add(self, other: Position) -> Position {
+        Position::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for Position {
+    type Output = Position;
+
+    fn sub(self, other: Position) -> Position {
+        Position::new(self.x - other.x, self.y - other.y)
+    }
 }
 
 // ============================================================================
 // ENTIDADES DO JOGO
 // ============================================================================
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// How snakes are born
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SnakeConfig {
+    pub length: usize,
+    pub direction: Direction,
+}
+
+impl Default for SnakeConfig {
+    fn default() -> Self {
+        SnakeConfig { length: 3, direction: Direction::Right }
+    }
+}
+
+impl SnakeConfig {
+    // Every cell of a snake born with its head at `head`, head first. The
+    // body trails behind, opposite to where it is heading
+    fn layout(&self, head: Position) -> Vec<Position> {
+        let behind = self.direction.opposite();
+        std::iter::successors(Some(head), |pos| Some(pos.move_in_direction(behind)))
+            .take(self.length.max(1))
+            .collect()
+    }
+}
+
+// Serialized by hand, to put its length() on the wire as "len"
+#[derive(Debug, Clone, Deserialize)]
 pub struct Snake {
-    pub body: Vec<Position>,
+    pub body: VecDeque<Position>, // serialized as an ordered array, head first
     pub direction: Direction,
     pub grow_next: bool,
+    pub score: i32,
+    pub alive: bool,
     
-    #[serde(skip_serializing)]
-    pub next_direction: Option<Direction>,
+    #[serde(default)]
+    pub next_directions: VecDeque<Direction>, // turns asked for, one is taken per tick
 }
 
 impl Snake {
     pub fn new(start_x: i32, start_y: i32) -> Self {
+        Self::with_config(start_x, start_y, SnakeConfig::default())
+    }
+
+    pub fn with_config(start_x: i32, start_y: i32, config: SnakeConfig) -> Self {
+        let body = VecDeque::from(config.layout(Position::new(start_x, start_y)));
         Snake {
-            body: vec![
-                Position::new(start_x, start_y),
-                Position::new(start_x - 1, start_y),
-                Position::new(start_x - 2, start_y),
-            ],
-            direction: Direction::Right,
-            next_direction: None,
+            body,
+            direction: config.direction,
+            next_directions: VecDeque::new(),
             grow_next: false,
+            score: 0,
+            alive: true,
         }
     }
 
+    pub fn length(&self) -> usize {
+        self.body.len()
+    }
+
     pub fn head(&self) -> Position {
-        self.body[0]
+        *self.body.front().unwrap()
     }
 
-    pub fn move_forward(&mut self) {
-        self.direction = self.next_direction.unwrap_or(self.direction);
-        let new_head = self.head().move_in_direction(self.direction);
-        self.next_direction = None;
-        self.body.insert(0, new_head);
+    pub fn move_forward(&mut self, wall_mode: WallMode, width: i32, height: i32) {
+        while let Some(direction) = self.next_directions.pop_front() {
+            if direction != self.direction.opposite() {
+                self.direction = direction;
+                break;
+            }
+        }
+        let mut new_head = self.head().move_in_direction(self.direction);
+        if wall_mode == WallMode::Wrap {
+            new_head = new_head.wrap(width, height);
+        }
+        self.body.push_front(new_head);
 
         if !self.grow_next {
-            self.body.pop();
+            self.body.pop_back();
         } else {
             self.grow_next = false;
         }
+        self.shrink_body_if_sparse();
     }
 
+    // A snake that got much shorter keeps its old allocation around,
+    // which adds up over many long-running games
+    fn shrink_body_if_sparse(&mut self) {
+        if self.body.capacity() > 2 * self.body.len() + BODY_SPARE_CAPACITY {
+            self.body.shrink_to_fit();
+        }
+    }
+
+    // Queued after the last turn asked for, so e.g. Right then Down around a
+    // corner both happen, one per tick. Anything reversing that last turn
+    // (or repeating it) is ignored
     pub fn change_direction(&mut self, new_direction: Direction) {
-        if !self.is_opposite_direction(new_direction) {
-            self.next_direction = Some(new_direction);
+        if new_direction != self.last_queued_direction()
+            && !self.is_opposite_direction(new_direction)
+            && self.next_directions.len() < MAX_QUEUED_DIRECTIONS
+        {
+            self.next_directions.push_back(new_direction);
         }
     }
 
     fn is_opposite_direction(&self, direction: Direction) -> bool {
-        matches!(
-            (self.direction, direction),
-            (Direction::Up, Direction::Down) |
-            (Direction::Down, Direction::Up) |
-            (Direction::Left, Direction::Right) |
-            (Direction::Right, Direction::Left)
-        )
+        self.last_queued_direction().opposite() == direction
+    }
+
+    fn last_queued_direction(&self) -> Direction {
+        self.next_directions.back().copied().unwrap_or(self.direction)
     }
 
     pub fn check_self_collision(&self) -> bool {
         let head = self.head();
-        self.body[1..].iter().any(|&pos| pos == head)
+        self.body.range(1..).any(|&pos| pos == head)
     }
 
     pub fn grow(&mut self) {
         self.grow_next = true;
     }
 
+    // Takes cells off the tail, false if that would leave no head
+    pub fn shrink(&mut self, cells: usize) -> bool {
+        if self.body.len() <= cells {
+            return false;
+        }
+        self.body.truncate(self.body.len() - cells);
+        self.shrink_body_if_sparse();
+        true
+    }
+
     pub fn is_colliding_with_walls(&self, width: i32, height: i32) -> bool {
         let head = self.head();
-        head.x < 0 || head.x >= width as i32 || head.y < 0 || head.y >= height as i32
+        head.x < 0 || head.x >= width || head.y < 0 || head.y >= height
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum FoodKind {
+    #[default]
+    Normal, // grows the snake, scores
+    Poison, // shrinks the snake, kills it if nothing is left
+    // power-ups, none of them grows the snake
+    SpeedBoost, // game runs twice as fast for a while
+    Slow, // game runs at half the speed for a while
+    Bonus, // extra points
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Effect {
+    SpeedBoost,
+    Slow,
+}
+
+// A power-up still going on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ActiveEffect {
+    pub effect: Effect,
+    pub ticks_left: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Food {
     pub position: Position,
+    #[serde(default)]
+    pub kind: FoodKind,
 }
 
 impl Food {
     pub fn new(position: Position) -> Self {
-        Self { position }
+        Self { position, kind: FoodKind::Normal }
+    }
+}
+
+// client_id -> snake. Besides the `snakes` map, the lowest-id snake is also
+// serialized under the old `snake` key, which single-player clients still draw
+#[derive(Debug, Clone, Default)]
+pub struct Snakes(HashMap<String, Snake>);
+
+impl Snakes {
+    // Ids in a fixed order, so every rule depending on it is deterministic
+    pub fn sorted_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.0.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn alive(&self) -> impl Iterator<Item = (&String, &Snake)> {
+        self.0.iter().filter(|(_, snake)| snake.alive)
+    }
+}
+
+impl Deref for Snakes {
+    type Target = HashMap<String, Snake>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
+impl DerefMut for Snakes {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Serialize for Snake {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(6))?;
+        map.serialize_entry("body", &self.body)?;
+        map.serialize_entry("direction", &self.direction)?;
+        map.serialize_entry("grow_next", &self.grow_next)?;
+        map.serialize_entry("score", &self.score)?;
+        map.serialize_entry("len", &self.length())?;
+        map.serialize_entry("alive", &self.alive)?;
+        map.end()
+    }
+}
+
+impl Serialize for Snakes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("snakes", &self.0)?;
+        if let Some((_, snake)) = self.0.iter().min_by_key(|(id, _)| *id) {
+            map.serialize_entry("snake", snake)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Snakes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Repr {
+            snakes: HashMap<String, Snake>,
+        }
+        Ok(Snakes(Repr::deserialize(deserializer)?.snakes))
+    }
+}
+
+// What happened in a single update(), in a nutshell
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TickOutcome {
+    pub ate: bool,
+    pub eater: Option<String>, // snake id that ate
+    pub grew_to: usize, // length the eater is growing to, 0 if nobody ate or it was poison
+    pub poisoned: bool, // what got eaten was poison
+    pub died: bool, // some snake died
+    pub ended: bool, // this update is what got the game over
+}
+
+// What changed in a single update(), enough for a client holding the previous
+// state to catch up. A snake id the client doesn't know about, or a tick that
+// isn't the previous one + 1, means it missed something and should resync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameDelta {
+    pub tick: u64,
+    pub snakes: HashMap<String, SnakeDelta>,
+    pub food: Option<Position>, // only when the food moved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub food_kind: Option<FoodKind>, // along with food
+    pub score: i32,
+    pub game_over: bool,
+    #[serde(default)]
+    pub won: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnakeDelta {
+    pub head: Position,
+    pub tail_popped: bool, // false when the snake grew
+    pub len: usize, // poison takes several cells at once, trim the body down to this
+    pub alive: bool,
+    pub score: i32,
+}
+
+// The state minus the snake bodies and the board layout: enough for a
+// scoreboard or a minimap, a fraction of the bytes of a game_state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameSummary {
+    pub tick: u64,
+    pub snakes: HashMap<String, SnakeSummary>,
+    pub food: Position,
+    pub score: i32,
+    pub game_over: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnakeSummary {
+    pub head: Position,
+    pub len: usize,
+    pub alive: bool,
+    pub score: i32,
+}
+
 // ============================================================================
 // ESTADO PRINCIPAL DO JOGO
 // ============================================================================
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameState {
-    pub snake: Snake,
+    #[serde(flatten)]
+    pub snakes: Snakes,
     pub food: Food,
-    pub score: i32,
+    pub score: i32, // best score among the snakes, the sole snake's in single-player
     pub game_over: bool,
+    #[serde(default)]
+    pub won: bool, // over because the board got filled up, not because of a crash
     pub width: i32,
     pub height: i32,
     pub interval: u16, // milliseconds
+    pub wall_mode: WallMode,
+    pub tick: u64, // number of updates so far, survives resets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_ramp: Option<SpeedRamp>,
+    #[serde(default)]
+    pub obstacles: HashSet<Position>, // interior walls, they never move
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_left_ms: Option<u64>, // countdown for time limited games
+    #[serde(skip)]
+    pub snake_config: SnakeConfig,
+    #[serde(skip)]
+    pub poison_chance: f64,
+    #[serde(skip)]
+    pub power_up_chance: f64, // 0 keeps them out of the game
+    #[serde(skip, default = "GameState::default_food_value")]
+    pub food_value: i32,
+    #[serde(skip)]
+    pub length_bonus: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub effects: Vec<ActiveEffect>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub bots: HashSet<String>, // snake ids steered by bot_direction
+    #[serde(default)]
+    pub versus: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<String>, // versus only, None when the last ones died together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<usize>, // enforced by the server, who knows who is in the room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>, // reaching it is a win, keeps marathon games from growing forever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>, // client id allowed to reset it, change its speed...
 
     #[serde(skip_serializing)]
     pub already_sent_gameovers_to : HashSet<String>,
+
+    #[serde(skip)]
+    last_delta: Option<GameDelta>,
+
+    #[serde(skip, default = "GameState::unseeded_rng")]
+    rng: Box<StdRng>,
 }
 
 impl GameState {
     pub fn new(width: i32, height: i32) -> Self {
-        let mut game = GameState {
-            snake: Snake::new(width as i32 / 2, height as i32 / 2),
+        Self::new_seeded(width, height, rand::random())
+    }
+
+    // Same seed, same food placements: handy for tests and replays
+    pub fn new_seeded(width: i32, height: i32, seed: u64) -> Self {
+        let mut game = Self::without_food(width, height, seed);
+        game.spawn_food();
+        game
+    }
+
+    // Nothing on the board yet, not even food, so no matter its size this
+    // doesn't go over its cells
+    fn without_food(width: i32, height: i32, seed: u64) -> Self {
+        GameState {
+            snakes: Snakes::default(),
             food: Food::new(Position::new(0, 0)),
             score: 0,
             game_over: false,
+            won: false,
             width,
             height,
             interval: 1500,
+            wall_mode: WallMode::default(),
+            tick: 0,
+            speed_ramp: None,
+            obstacles: HashSet::new(),
+            time_limit_ms: None,
+            time_left_ms: None,
+            snake_config: SnakeConfig::default(),
+            poison_chance: 0.0,
+            food_value: Self::default_food_value(),
+            length_bonus: false,
+            power_up_chance: 0.0,
+            effects: Vec::new(),
+            bots: HashSet::new(),
+            versus: false,
+            winner: None,
+            max_players: None,
+            max_length: None,
+            owner: None,
             already_sent_gameovers_to: HashSet::new(),
+            last_delta: None,
+            rng: Box::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn with_wall_mode(mut self, wall_mode: WallMode) -> Self {
+        self.wall_mode = wall_mode;
+        self
+    }
+
+    pub fn with_speed_ramp(mut self, speed_ramp: SpeedRamp) -> Self {
+        self.interval = speed_ramp.start_interval;
+        self.speed_ramp = Some(speed_ramp);
+        self
+    }
+
+    // Scatters `count` wall blocks (fewer if the board is too small), keeping
+    // clear of the center where the first snake spawns. Placement comes from
+    // the game's rng, so the same seed gives the same maze
+    pub fn with_obstacles(mut self, count: usize) -> Self {
+        let center = Position::new(self.width / 2, self.height / 2);
+        let candidates: Vec<Position> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| Position::new(x, y)))
+            .filter(|pos| (pos.x - center.x).abs() > 3 || (pos.y - center.y).abs() > 1)
+            .collect();
+        self.obstacles = candidates
+            .choose_multiple(self.rng.as_mut(), count)
+            .cloned()
+            .collect();
+        if self.obstacles.contains(&self.food.position) {
+            self.spawn_food();
+        }
+        self
+    }
+
+    // Snakes longer than the board allows are cut down to fit
+    pub fn with_snake_config(mut self, snake_config: SnakeConfig) -> Self {
+        let room = match snake_config.direction {
+            Direction::Left | Direction::Right => self.width,
+            Direction::Up | Direction::Down => self.height,
         };
-        game.spawn_food();
-        game
+        // one cell is left free ahead of the head
+        let max_length = (room - 1).max(1) as usize;
+        self.snake_config = SnakeConfig {
+            length: snake_config.length.clamp(1, max_length),
+            ..snake_config
+        };
+        self
     }
 
-    pub fn spawn_food(&mut self) {
-        let mut x = ((self.score * 7 + 3) % (self.width as i32)) as i32;
-        let mut y = ((self.score * 11 + 5) % (self.height as i32)) as i32;
-        
-        let snake_positions: HashSet<Position> = self.snake.body.iter().cloned().collect();
-        
-        for _ in 0..100 {
-            let pos = Position::new(x, y);
-            if !snake_positions.contains(&pos) {
-                self.food.position = pos;
-                return;
-            }
-            x = (x + 1) % (self.width as i32);
-            if x == 0 {
-                y = (y + 1) % (self.height as i32);
+    // Odds of each new food being poison, from the next spawned one on
+    pub fn with_poison_chance(mut self, chance: f64) -> Self {
+        self.poison_chance = chance.clamp(0.0, 1.0);
+        self
+    }
+
+    // Same as with_poison_chance. Power-ups are rolled after poison, so the
+    // two don't add up to more than 1
+    pub fn with_power_up_chance(mut self, chance: f64) -> Self {
+        self.power_up_chance = chance.clamp(0.0, 1.0);
+        self
+    }
+
+    // Points per normal food, plus the snake's length with `length_bonus`
+    // (the longer it gets, the more each food is worth)
+    pub fn with_food_value(mut self, food_value: i32, length_bonus: bool) -> Self {
+        self.food_value = food_value;
+        self.length_bonus = length_bonus;
+        self
+    }
+
+    // Milliseconds between updates, counting the power-ups in effect
+    pub fn effective_interval(&self) -> u16 {
+        self.effects.iter().fold(self.interval, |interval, active| match active.effect {
+            Effect::SpeedBoost => interval / 2,
+            Effect::Slow => interval.saturating_mul(2),
+        })
+    }
+
+    // Once two or more snakes are on the board, the game ends as soon as
+    // there's only one left alive
+    pub fn with_versus(mut self, versus: bool) -> Self {
+        self.versus = versus;
+        self
+    }
+
+    pub fn with_max_players(mut self, max_players: usize) -> Self {
+        self.max_players = Some(max_players);
+        self
+    }
+
+    // Set it after with_snake_config: it is kept longer than the snakes start
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = Some(max_length.max(self.snake_config.length + 1));
+        self
+    }
+
+    pub fn with_time_limit(mut self, time_limit_ms: u64) -> Self {
+        self.time_limit_ms = Some(time_limit_ms);
+        self.time_left_ms = Some(time_limit_ms);
+        self
+    }
+
+    // Runs the countdown of a time limited game, returns true when this is
+    // what ended it
+    pub fn advance_clock(&mut self, elapsed_ms: u64) -> bool {
+        match self.time_left_ms.as_mut() {
+            Some(time_left) if !self.game_over => {
+                *time_left = time_left.saturating_sub(elapsed_ms);
+                self.game_over = *time_left == 0;
+                self.game_over
+            },
+            _ => false,
+        }
+    }
+
+    fn default_food_value() -> i32 {
+        10
+    }
+
+    fn unseeded_rng() -> Box<StdRng> {
+        Box::new(StdRng::from_os_rng())
+    }
+
+    // Games with the same key have comparable scores, they share a high
+    // score board
+    pub fn board_key(&self) -> String {
+        let wall_mode = match self.wall_mode {
+            WallMode::Solid => "solid",
+            WallMode::Wrap => "wrap",
+        };
+        let mut key = format!("{}x{}-{}", self.width, self.height, wall_mode);
+        // other scoring rules, other scores
+        if self.food_value != Self::default_food_value() {
+            key.push_str(&format!("-food{}", self.food_value));
+        }
+        if self.length_bonus {
+            key.push_str("-lenbonus");
+        }
+        key
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.game_over
+    }
+
+    // Cells taken by living snakes or obstacles
+    pub fn occupied_cells(&self) -> usize {
+        self.occupied_positions().len()
+    }
+
+    fn occupied_positions(&self) -> HashSet<Position> {
+        self.snakes
+            .alive()
+            .flat_map(|(_, snake)| snake.body.iter().cloned())
+            .chain(self.obstacles.iter().cloned())
+            .collect()
+    }
+
+    // Spawns a snake for `id`, at the board center if that's free or
+    // somewhere random otherwise. Returns false if there's no room left.
+    pub fn add_snake(&mut self, id: &str) -> bool {
+        let mut occupied = self.occupied_positions();
+        occupied.insert(self.food.position);
+        // the whole body plus the cell ahead of the head must be free
+        let fits = |head: &Position| {
+            let ahead = head.move_in_direction(self.snake_config.direction);
+            self.snake_config.layout(*head).into_iter().chain([ahead]).all(|pos| {
+                pos.x >= 0 && pos.x < self.width && pos.y >= 0 && pos.y < self.height
+                    && !occupied.contains(&pos)
+            })
+        };
+        let center = Position::new(self.width / 2, self.height / 2);
+        let head = if fits(&center) {
+            Some(center)
+        } else {
+            let candidates: Vec<Position> = (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| Position::new(x, y)))
+                .filter(|pos| fits(pos))
+                .collect();
+            candidates.choose(self.rng.as_mut()).cloned()
+        };
+        match head {
+            Some(head) => {
+                self.snakes.insert(id.to_string(), Snake::with_config(head.x, head.y, self.snake_config));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Same as add_snake, for a bot. Returns its id, None if there's no room
+    pub fn add_bot(&mut self) -> Option<String> {
+        let id = (1..).map(|n| format!("bot-{}", n)).find(|id| !self.snakes.contains_key(id))?;
+        if !self.add_snake(&id) {
+            return None;
+        }
+        self.bots.insert(id.clone());
+        Some(id)
+    }
+
+    pub fn remove_snake(&mut self, id: &str) {
+        self.snakes.remove(id);
+        self.refresh_status();
+    }
+
+    // Same snake under another id, for a player back on a new connection
+    pub fn rename_snake(&mut self, from: &str, to: &str) {
+        if let Some(snake) = self.snakes.remove(from) {
+            self.snakes.insert(to.to_string(), snake);
+        }
+        for id in [&mut self.owner, &mut self.winner] {
+            if id.as_deref() == Some(from) {
+                *id = Some(to.to_string());
             }
         }
-        
-        self.food.position = Position::new(0, 0);
     }
 
-    pub fn update(&mut self) {
+    // Puts a normal food right there instead of a random cell, for tests and
+    // scripted boards. Nothing stops it from landing on a snake
+    pub fn place_food(&mut self, position: Position) {
+        self.food = Food::new(position);
+    }
+
+    pub fn spawn_food(&mut self) {
+        let snake_positions = self.occupied_positions();
+        let free_cells: Vec<Position> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| Position::new(x, y)))
+            .filter(|pos| !snake_positions.contains(pos))
+            .collect();
+
+        match free_cells.choose(self.rng.as_mut()) {
+            Some(&pos) => {
+                let kind = if self.poison_chance > 0.0 && self.rng.random_bool(self.poison_chance) {
+                    FoodKind::Poison
+                } else if self.power_up_chance > 0.0 && self.rng.random_bool(self.power_up_chance) {
+                    *[FoodKind::SpeedBoost, FoodKind::Slow, FoodKind::Bonus].choose(self.rng.as_mut()).unwrap()
+                } else {
+                    FoodKind::Normal
+                };
+                self.food = Food { position: pos, kind };
+            },
+            // snakes fill the whole board, nothing left to eat
+            None => {
+                self.game_over = true;
+                self.won = self.snakes.alive().next().is_some();
+            },
+        }
+    }
+
+    // Advances every living snake one cell. All snakes move first, then:
+    // - a snake whose head is alone on the food eats it. Several heads on the
+    //   food is a head-on crash like any other, nobody eats it
+    // - a snake dies when its head hits a wall, an obstacle, itself or any
+    //   cell of another living snake (heads meeting in the same cell kill both). Deaths are
+    //   decided before being applied, so the order snakes are visited in
+    //   doesn't matter
+    // The game is over once no snake is left alive.
+    pub fn update(&mut self) -> TickOutcome {
+        self.last_delta = None;
         if self.game_over {
-            return;
+            return TickOutcome::default();
+        }
+
+        for active in self.effects.iter_mut() {
+            active.ticks_left -= 1;
+        }
+        self.effects.retain(|active| active.ticks_left > 0);
+
+        let bot_turns: Vec<(String, Direction)> = self.snakes
+            .alive()
+            .filter(|(id, _)| self.bots.contains(*id))
+            .map(|(id, snake)| (id.clone(), bot_direction(self, snake)))
+            .collect();
+        for (id, direction) in bot_turns {
+            let snake = self.snakes.get_mut(&id).unwrap();
+            snake.next_directions.clear();
+            snake.change_direction(direction);
+        }
+
+        let ids: Vec<String> = self.snakes
+            .sorted_ids()
+            .into_iter()
+            .filter(|id| self.snakes[id].alive)
+            .collect();
+        let lengths_before: Vec<usize> = ids.iter().map(|id| self.snakes[id].length()).collect();
+        for id in ids.iter() {
+            let snake = self.snakes.get_mut(id).unwrap();
+            snake.move_forward(self.wall_mode, self.width, self.height);
         }
 
-        self.snake.move_forward();
+        // Verifica se comeu a comida
+        let mut on_food = ids.iter().filter(|id| self.snakes[*id].head() == self.food.position);
+        let eater = match (on_food.next(), on_food.next()) {
+            (Some(id), None) => Some(id),
+            _ => None, // they crash into each other right there
+        };
+        let poisoned = eater.is_some() && self.food.kind == FoodKind::Poison;
+        let grew = eater.is_some() && self.food.kind == FoodKind::Normal;
+        let mut starved = None;
+        if let Some(id) = eater {
+            let snake = self.snakes.get_mut(id).unwrap();
+            let timed_effect = match self.food.kind {
+                FoodKind::Normal => {
+                    snake.grow();
+                    snake.score = snake.score.saturating_add(self.food_value);
+                    if self.length_bonus {
+                        snake.score = snake.score.saturating_add(snake.length() as i32);
+                    }
+                    if let Some(ramp) = self.speed_ramp {
+                        self.interval = self.interval.saturating_sub(ramp.step).max(ramp.min_interval);
+                    }
+                    None
+                },
+                FoodKind::Poison => {
+                    if !snake.shrink(POISON_SHRINK) {
+                        starved = Some(id);
+                    }
+                    None
+                },
+                FoodKind::Bonus => {
+                    snake.score = snake.score.saturating_add(BONUS_POINTS);
+                    None
+                },
+                FoodKind::SpeedBoost => Some(Effect::SpeedBoost),
+                FoodKind::Slow => Some(Effect::Slow),
+            };
+            if let Some(effect) = timed_effect {
+                // eating the same kind again just restarts its timer
+                self.effects.retain(|active| active.effect != effect);
+                self.effects.push(ActiveEffect { effect, ticks_left: POWER_UP_TICKS });
+            }
+        }
 
         // Verifica colisões
-        if self.snake.is_colliding_with_walls(self.width, self.height) 
-            || self.snake.check_self_collision() {
+        let dead: Vec<&String> = ids
+            .iter()
+            .filter(|id| {
+                let snake = &self.snakes[*id];
+                let head = snake.head();
+                snake.is_colliding_with_walls(self.width, self.height)
+                    || self.obstacles.contains(&head)
+                    || snake.check_self_collision()
+                    || ids.iter().any(|other| other != *id && self.snakes[other].body.contains(&head))
+                    || starved == Some(*id)
+            })
+            .collect();
+        let died = !dead.is_empty();
+        for id in dead {
+            self.snakes.get_mut(id).unwrap().alive = false;
+        }
+
+        if eater.is_some() {
+            self.spawn_food();
+        }
+        self.refresh_status();
+
+        self.tick += 1;
+        self.last_delta = Some(GameDelta {
+            tick: self.tick,
+            snakes: ids
+                .iter()
+                .zip(lengths_before)
+                .map(|(id, len_before)| {
+                    let snake = &self.snakes[id];
+                    (id.clone(), SnakeDelta {
+                        head: snake.head(),
+                        tail_popped: snake.length() == len_before,
+                        len: snake.length(),
+                        alive: snake.alive,
+                        score: snake.score,
+                    })
+                })
+                .collect(),
+            food: eater.map(|_| self.food.position),
+            food_kind: eater.map(|_| self.food.kind),
+            score: self.score,
+            game_over: self.game_over,
+            won: self.won,
+        });
+
+        TickOutcome {
+            ate: eater.is_some(),
+            eater: eater.cloned(),
+            grew_to: eater.filter(|_| grew).map(|id| self.snakes[id].length() + 1).unwrap_or(0),
+            poisoned,
+            died,
+            ended: self.game_over,
+        }
+    }
+
+    // Compact alternative to the json game_state. All numbers big endian:
+    //   tick u64, score i32, game_over u8 (0 running, 1 over, 2 won), width u16, height u16,
+    //   food x i16, food y i16,
+    //   food kind u8 (0 normal, 1 poison, 2 speed boost, 3 slow, 4 bonus),
+    //   snake count u16, then for each snake (in id order):
+    //     id length u16, id (utf8), alive u8, score i32, direction u8,
+    //     body length u16, body cells as (x i16, y i16) pairs, head first
+    //   obstacle count u16, obstacles as (x i16, y i16) pairs
+    // Coordinates are signed: a snake that died on a wall has its head outside
+    // the board.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let push_position = |out: &mut Vec<u8>, pos: &Position| {
+            out.extend((pos.x as i16).to_be_bytes());
+            out.extend((pos.y as i16).to_be_bytes());
+        };
+        out.extend(self.tick.to_be_bytes());
+        out.extend(self.score.to_be_bytes());
+        out.push(match (self.game_over, self.won) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 2,
+        });
+        out.extend((self.width as u16).to_be_bytes());
+        out.extend((self.height as u16).to_be_bytes());
+        push_position(&mut out, &self.food.position);
+        out.push(match self.food.kind {
+            FoodKind::Normal => 0,
+            FoodKind::Poison => 1,
+            FoodKind::SpeedBoost => 2,
+            FoodKind::Slow => 3,
+            FoodKind::Bonus => 4,
+        });
+        out.extend((self.snakes.len() as u16).to_be_bytes());
+        for id in self.snakes.sorted_ids() {
+            let snake = &self.snakes[&id];
+            out.extend((id.len() as u16).to_be_bytes());
+            out.extend(id.as_bytes());
+            out.push(snake.alive as u8);
+            out.extend(snake.score.to_be_bytes());
+            out.push(match snake.direction {
+                Direction::Up => 0,
+                Direction::Down => 1,
+                Direction::Left => 2,
+                Direction::Right => 3,
+            });
+            out.extend((snake.body.len() as u16).to_be_bytes());
+            for pos in snake.body.iter() {
+                push_position(&mut out, pos);
+            }
+        }
+        out.extend((self.obstacles.len() as u16).to_be_bytes());
+        for pos in self.obstacles.iter() {
+            push_position(&mut out, pos);
+        }
+        out
+    }
+
+    // Reads back what to_binary wrote. Settings that aren't in there (interval,
+    // wall mode...) come out as defaults
+    pub fn from_binary(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = BinaryReader { data };
+        let tick = reader.u64()?;
+        let score = reader.i32()?;
+        let status = reader.u8()?;
+        let width = reader.u16()? as i32;
+        let height = reader.u16()? as i32;
+        if width > MAX_BINARY_BOARD_SIDE || height > MAX_BINARY_BOARD_SIDE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("board {}x{} is too big", width, height)));
+        }
+        let mut game = GameState::without_food(width, height, 0);
+        game.tick = tick;
+        game.score = score;
+        game.game_over = status != 0;
+        game.won = status == 2;
+        game.food = Food {
+            position: reader.position()?,
+            kind: match reader.u8()? {
+                0 => FoodKind::Normal,
+                1 => FoodKind::Poison,
+                2 => FoodKind::SpeedBoost,
+                3 => FoodKind::Slow,
+                4 => FoodKind::Bonus,
+                other => return Err(Error::new(ErrorKind::InvalidData, format!("bad food kind {}", other))),
+            },
+        };
+        for _ in 0..reader.u16()? {
+            let id_len = reader.u16()? as usize;
+            let id = String::from_utf8(reader.take(id_len)?.to_vec())
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            let alive = reader.u8()? != 0;
+            let score = reader.i32()?;
+            let direction = match reader.u8()? {
+                0 => Direction::Up,
+                1 => Direction::Down,
+                2 => Direction::Left,
+                3 => Direction::Right,
+                other => return Err(Error::new(ErrorKind::InvalidData, format!("bad direction {}", other))),
+            };
+            let body = (0..reader.u16()?)
+                .map(|_| reader.position())
+                .collect::<Result<VecDeque<Position>, Error>>()?;
+            if body.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("snake {} has no body", id)));
+            }
+            game.snakes.insert(id, Snake {
+                body,
+                direction,
+                next_directions: VecDeque::new(),
+                grow_next: false,
+                score,
+                alive,
+            });
+        }
+        game.obstacles = (0..reader.u16()?)
+            .map(|_| reader.position())
+            .collect::<Result<HashSet<Position>, Error>>()?;
+        Ok(game)
+    }
+
+    // Changes made by the last update(), None if it didn't run the game
+    // (e.g. it was already over)
+    pub fn last_delta(&self) -> Option<&GameDelta> {
+        self.last_delta.as_ref()
+    }
+
+    pub fn summary(&self) -> GameSummary {
+        GameSummary {
+            tick: self.tick,
+            snakes: self.snakes.iter().map(|(id, snake)| (id.clone(), SnakeSummary {
+                head: snake.head(),
+                len: snake.length(),
+                alive: snake.alive,
+                score: snake.score,
+            })).collect(),
+            food: self.food.position,
+            score: self.score,
+            game_over: self.game_over,
+            winner: self.winner.clone(),
+        }
+    }
+
+    fn refresh_status(&mut self) {
+        self.score = self.snakes.values().map(|snake| snake.score).max().unwrap_or(0);
+        let alive: Vec<&String> = self.snakes.alive().map(|(id, _)| id).collect();
+        if self.versus && !self.game_over && self.snakes.len() >= 2 && alive.len() <= 1 {
+            self.winner = alive.first().map(|id| id.to_string());
+            self.game_over = true;
+        }
+        let long_enough = self.max_length.and_then(|max_length| {
+            self.snakes.sorted_ids().into_iter().find(|id| self.snakes[id].alive && self.snakes[id].length() >= max_length)
+        });
+        if let Some(id) = long_enough.filter(|_| !self.game_over) {
+            self.game_over = true;
+            self.won = true;
+            if self.versus {
+                self.winner = Some(id);
+            }
+        }
+        // bots playing on their own don't keep a game going. An empty
+        // board isn't over though, it's waiting for someone
+        if !self.snakes.is_empty() && alive.iter().all(|id| self.bots.contains(*id)) {
             self.game_over = true;
+        }
+    }
+
+    pub fn handle_input(&mut self, snake_id: &str, direction: Direction) {
+        if self.game_over {
             return;
         }
+        if let Some(snake) = self.snakes.get_mut(snake_id) {
+            if snake.alive {
+                snake.change_direction(direction);
+            }
+        }
+    }
 
-        // Verifica se comeu a comida
-        if self.snake.head() == self.food.position {
-            self.snake.grow();
-            self.score += 10;
+    // Fresh board with the same players and settings on it
+    pub fn reset(&mut self) {
+        let ids = self.snakes.sorted_ids();
+        let interval = match self.speed_ramp {
+            Some(ramp) => ramp.start_interval, // back to the slow start
+            None => self.interval,
+        };
+        let tick = self.tick;
+        let speed_ramp = self.speed_ramp;
+        let obstacles = std::mem::take(&mut self.obstacles);
+        let time_limit_ms = self.time_limit_ms;
+        let bots = std::mem::take(&mut self.bots);
+        let max_players = self.max_players;
+        let max_length = self.max_length;
+        let owner = self.owner.take();
+        *self = Self::new(self.width, self.height)
+            .with_wall_mode(self.wall_mode)
+            .with_snake_config(self.snake_config)
+            .with_poison_chance(self.poison_chance)
+            .with_food_value(self.food_value, self.length_bonus)
+            .with_power_up_chance(self.power_up_chance)
+            .with_versus(self.versus);
+        self.max_players = max_players;
+        self.max_length = max_length;
+        self.owner = owner;
+        self.time_limit_ms = time_limit_ms;
+        self.time_left_ms = time_limit_ms; // the clock starts over too
+        self.interval = interval;
+        self.tick = tick;
+        self.speed_ramp = speed_ramp;
+        // same maze, the food just can't be inside it
+        self.obstacles = obstacles;
+        if self.obstacles.contains(&self.food.position) {
             self.spawn_food();
         }
+        self.bots = bots;
+        for id in ids {
+            self.add_snake(&id);
+        }
     }
+}
+
+// ============================================================================
+// JOGADOR AUTOMÁTICO
+// ============================================================================
+
+// Greedy: the turn that gets the head closest to the food without dying
+// right away. No lookahead, so it happily walks into dead ends. Poison is
+// avoided rather than chased, and with nothing safe it just keeps going
+pub fn bot_direction(state: &GameState, snake: &Snake) -> Direction {
+    let occupied = state.occupied_positions();
+    let target = (state.food.kind != FoodKind::Poison).then_some(state.food.position);
+    let distance = |from: Position, to: Position| {
+        let (mut dx, mut dy) = ((from.x - to.x).abs(), (from.y - to.y).abs());
+        if state.wall_mode == WallMode::Wrap {
+            dx = dx.min(state.width - dx);
+            dy = dy.min(state.height - dy);
+        }
+        dx + dy
+    };
+    [Direction::Up, Direction::Down, Direction::Left, Direction::Right]
+        .into_iter()
+        .filter(|direction| *direction != snake.direction.opposite())
+        .filter_map(|direction| {
+            let mut next = snake.head().move_in_direction(direction);
+            if state.wall_mode == WallMode::Wrap {
+                next = next.wrap(state.width, state.height);
+            }
+            let off_board = next.x < 0 || next.x >= state.width || next.y < 0 || next.y >= state.height;
+            let poison = target.is_none() && next == state.food.position;
+            if off_board || occupied.contains(&next) || poison {
+                return None;
+            }
+            let cost = match target {
+                Some(food) => distance(next, food),
+                None => (direction != snake.direction) as i32, // rather not turn
+            };
+            Some((direction, cost))
+        })
+        .min_by_key(|(_, cost)| *cost)
+        .map(|(direction, _)| direction)
+        .unwrap_or(snake.direction)
+}
+
+// Walks through GameState::to_binary's output
+struct BinaryReader<'a> {
+    data: &'a [u8],
+}
 
-    pub fn handle_input(&mut self, direction: Direction) {
-        if !self.game_over {
-            self.snake.change_direction(direction);
+impl<'a> BinaryReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "binary game state is truncated"));
         }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
     }
 
-    pub fn reset(&mut self) {
-        *self = Self::new(self.width, self.height);
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn position(&mut self) -> Result<Position, Error> {
+        Ok(Position::new(self.i16()? as i32, self.i16()? as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opposite_directions() {
+        assert_eq!(Direction::Up.opposite(), Direction::Down);
+        assert_eq!(Direction::Down.opposite(), Direction::Up);
+        assert_eq!(Direction::Left.opposite(), Direction::Right);
+        assert_eq!(Direction::Right.opposite(), Direction::Left);
+        for direction in [Direction::Up, Direction::Down, Direction::Left, Direction::Right] {
+            assert_eq!(direction.opposite().opposite(), direction);
+        }
+    }
+
+    #[test]
+    fn wrapping_brings_negative_coordinates_back_on_the_board() {
+        assert_eq!(Position::new(-1, -1).wrap(20, 10), Position::new(19, 9));
+        assert_eq!(Position::new(-21, -10).wrap(20, 10), Position::new(19, 0));
+        assert_eq!(Position::new(20, 10).wrap(20, 10), Position::new(0, 0));
+        assert_eq!(Position::new(5, 7).wrap(20, 10), Position::new(5, 7));
+    }
+
+    #[test]
+    fn positions_add_and_subtract() {
+        let a = Position::new(3, -2);
+        let b = Position::new(-5, 4);
+        assert_eq!(a + b, Position::new(-2, 2));
+        assert_eq!(a - b, Position::new(8, -6));
+        assert_eq!(a + b - b, a);
+    }
+
+    // A 20x20 board with snake "a" born in the middle (head at 10,10,
+    // heading right) and the food out of its way in a corner
+    fn game_with_snake() -> GameState {
+        let mut game = GameState::new_seeded(20, 20, 7);
+        game.place_food(Position::new(0, 0));
+        assert!(game.add_snake("a"));
+        game
+    }
+
+    #[test]
+    fn hitting_a_wall_kills_the_snake() {
+        let mut game = game_with_snake();
+        for _ in 0..9 {
+            game.update();
+        }
+        assert!(game.snakes["a"].alive);
+        assert_eq!(game.snakes["a"].head(), Position::new(19, 10));
+
+        let outcome = game.update();
+        assert!(outcome.died);
+        assert!(outcome.ended);
+        assert!(!game.snakes["a"].alive);
+        assert!(game.game_over);
+    }
+
+    #[test]
+    fn running_into_itself_kills_the_snake() {
+        let mut game = GameState::new_seeded(20, 20, 7)
+            .with_snake_config(SnakeConfig { length: 5, direction: Direction::Right });
+        game.place_food(Position::new(0, 0));
+        game.add_snake("a");
+        // a tight u-turn, right back into its own body
+        for direction in [Direction::Down, Direction::Left, Direction::Up] {
+            game.handle_input("a", direction);
+        }
+        game.update();
+        game.update();
+        assert!(game.snakes["a"].alive);
+
+        game.update();
+        assert!(!game.snakes["a"].alive);
+        assert!(game.game_over);
+    }
+
+    #[test]
+    fn eating_scores_and_grows_on_the_next_move() {
+        let mut game = game_with_snake();
+        game.place_food(Position::new(11, 10));
+
+        let outcome = game.update();
+        assert!(outcome.ate);
+        assert_eq!(outcome.eater.as_deref(), Some("a"));
+        assert_eq!(outcome.grew_to, 4);
+        assert_eq!(game.snakes["a"].score, 10);
+        assert_eq!(game.score, 10);
+        assert_ne!(game.food.position, Position::new(11, 10));
+        assert_eq!(game.snakes["a"].length(), 3);
+
+        game.update();
+        assert_eq!(game.snakes["a"].length(), 4);
+        assert_eq!(game.snakes["a"].head(), Position::new(12, 10));
+        game.update();
+        assert_eq!(game.snakes["a"].length(), 4);
+    }
+
+    #[test]
+    fn heads_meeting_on_the_food_crash_and_nobody_eats() {
+        let mut game = GameState::new_seeded(20, 20, 7);
+        game.place_food(Position::new(6, 5));
+        // both heads one step away from the food, from the left and from above
+        game.snakes.insert("b".to_string(), Snake::with_config(6, 4, SnakeConfig { length: 3, direction: Direction::Down }));
+        game.snakes.insert("a".to_string(), Snake::with_config(5, 5, SnakeConfig { length: 3, direction: Direction::Right }));
+
+        let outcome = game.update();
+        assert_eq!(outcome.eater, None);
+        for id in ["a", "b"] {
+            assert!(!game.snakes[id].alive);
+            assert!(!game.snakes[id].grow_next);
+            assert_eq!(game.snakes[id].score, 0);
+        }
+        assert!(outcome.died);
+        assert!(game.game_over);
+        // nobody ate it, it's still there
+        assert_eq!(game.food.position, Position::new(6, 5));
     }
+
+    #[test]
+    fn reversing_is_ignored() {
+        let mut game = game_with_snake();
+        game.handle_input("a", Direction::Left);
+        game.update();
+        assert_eq!(game.snakes["a"].direction, Direction::Right);
+        assert_eq!(game.snakes["a"].head(), Position::new(11, 10));
+        assert!(game.snakes["a"].alive);
+
+        // nor can a queued turn be undone by the next one
+        game.handle_input("a", Direction::Up);
+        game.handle_input("a", Direction::Down);
+        game.update();
+        game.update();
+        assert_eq!(game.snakes["a"].direction, Direction::Up);
+        assert_eq!(game.snakes["a"].head(), Position::new(11, 8));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut game = GameState::new_seeded(24, 16, 3).with_obstacles(6);
+        game.add_snake("a");
+        game.add_snake("bob");
+        // died on the left wall, head off the board
+        let mut crashed = Snake::with_config(-1, 3, SnakeConfig { length: 4, direction: Direction::Left });
+        crashed.alive = false;
+        crashed.score = 40;
+        game.snakes.insert("c".to_string(), crashed);
+        game.food.kind = FoodKind::Poison;
+        game.update();
+
+        let decoded = GameState::from_binary(&game.to_binary()).unwrap();
+        assert_eq!(decoded.tick, game.tick);
+        assert_eq!(decoded.score, game.score);
+        assert_eq!(decoded.game_over, game.game_over);
+        assert_eq!(decoded.won, game.won);
+        assert_eq!((decoded.width, decoded.height), (24, 16));
+        assert_eq!(decoded.food.position, game.food.position);
+        assert_eq!(decoded.food.kind, game.food.kind);
+        assert_eq!(decoded.obstacles, game.obstacles);
+        assert_eq!(decoded.obstacles.len(), 6);
+        assert_eq!(decoded.snakes.sorted_ids(), ["a", "bob", "c"]);
+        for (id, snake) in game.snakes.iter() {
+            let other = &decoded.snakes[id];
+            assert_eq!(other.body, snake.body);
+            assert_eq!(other.direction, snake.direction);
+            assert_eq!(other.score, snake.score);
+            assert_eq!(other.alive, snake.alive);
+        }
+        assert_eq!(decoded.snakes["c"].head(), Position::new(-1, 3));
+    }
+
+    #[test]
+    fn truncated_binary_is_an_unexpected_eof() {
+        let mut game = GameState::new_seeded(20, 20, 7);
+        game.add_snake("a");
+        let data = game.to_binary();
+        for len in [0, 10, data.len() - 1] {
+            let err = GameState::from_binary(&data[..len]).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn oversized_binary_boards_are_rejected() {
+        let mut data = GameState::new_seeded(20, 20, 7).to_binary();
+        // width and height come right after tick, score and status
+        data[13..17].copy_from_slice(&[0xff; 4]);
+        let err = GameState::from_binary(&data).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
 }
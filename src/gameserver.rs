@@ -1,16 +1,269 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 
 
-use crate::game::{GameState};
+use crate::game::{GameState, JoinGame, Size, SnakeConfig, SpeedRamp};
 use crate::protocol::{parse_client_message, ServerMessage, *};
 use crate::http::*;
 use std::collections::{HashMap, HashSet};
-use std::time::{Duration};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 
-static MINIMUM_TICK : i32 = 50;
-static MAX_HTTP_BUFFER_LEN : usize = 8192;
+// How far back the input rate looks, long enough that a quick burst of
+// turns doesn't count as sustained
+static INPUT_RATE_WINDOW_SECS: f64 = 3.0;
+// High scores go to disk at most this often, however fast they come
+static HIGH_SCORES_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+// ============================================================================
+// CONFIGURAÇÃO DO SERVIDOR
+// ============================================================================
+
+#[derive(Debug, Clone)]
+pub struct GameServerConfig {
+    // How often games are looked at. Each game runs on its own `interval`,
+    // counted down by this much per tick, so its updates land on ticks and
+    // none can come faster than this
+    pub min_tick_ms: u64,
+    pub default_interval: u16, // milliseconds between updates of a new game
+    pub max_interval: u16, // slowest a client may set a game to, the fastest is min_tick_ms
+    pub max_food_value: i32, // most points a JoinGame may make each food worth
+    pub min_board_width: i32, // small enough boards don't even fit a starting snake
+    pub min_board_height: i32,
+    pub max_board_width: i32,
+    pub max_board_height: i32,
+    // Safety net against games nobody is playing anymore: any game running
+    // longer than this is force-ended and its score recorded
+    pub max_game_duration: Option<Duration>,
+    // How long a finished game is kept around once everyone is gone from it
+    pub finished_game_grace_period: Duration,
+    pub highscore_file: PathBuf, // where high scores are kept between restarts
+    pub idle_timeout: Duration, // clients silent for longer than this get kicked
+    // Websocket clients quiet for this long get a ping, their pong keeps them
+    // from being idle. Keep it well under idle_timeout
+    pub ping_interval: Duration,
+    pub max_messages_per_tick: u32, // game messages a client may send per tick, the rest is dropped
+    // Sustained inputs per second past which a client is taken for a bot:
+    // it keeps playing, but its scores stay off the leaderboard
+    pub max_inputs_per_second: f64,
+    pub static_dir: PathBuf, // web root for the plain http files
+    pub index_file: String, // what "/" serves
+    pub session_timeout: Duration, // how long a dropped client has to resume its session
+    pub max_connections: Option<usize>, // new connections past this are turned away
+    pub max_chat_len: usize, // characters
+    pub max_username_len: usize, // characters
+    pub max_players: usize, // per game, spectators included. JoinGame may ask for less
+    pub max_queued_frames: usize, // unsent messages a client may pile up before it gets kicked
+    // Other origins whose pages may use the http endpoints ("*" for any).
+    // Empty means same origin only
+    pub allowed_origins: Vec<String>,
+    pub subprotocols: Vec<String>, // Sec-WebSocket-Protocol values we accept
+    // POST /highscore takes any score anyone sends, no game behind it.
+    // Off unless the leaderboard is meant to be open like that
+    pub score_submission: bool,
+}
+
+impl Default for GameServerConfig {
+    fn default() -> Self {
+        GameServerConfig {
+            min_tick_ms: 50,
+            default_interval: 1500,
+            max_interval: 5000,
+            max_food_value: 100,
+            min_board_width: 8,
+            min_board_height: 8,
+            max_board_width: 128,
+            max_board_height: 128,
+            max_game_duration: None,
+            finished_game_grace_period: Duration::from_secs(30),
+            highscore_file: PathBuf::from("highscores.json"),
+            idle_timeout: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(20),
+            max_messages_per_tick: 5,
+            max_inputs_per_second: 15.0,
+            static_dir: PathBuf::from("public"),
+            index_file: "index.html".to_string(),
+            session_timeout: Duration::from_secs(60),
+            max_connections: None,
+            max_chat_len: 200,
+            max_username_len: 24,
+            max_players: 8,
+            max_queued_frames: 64,
+            allowed_origins: Vec::new(),
+            subprotocols: vec!["snake-v1".to_string()],
+            score_submission: false,
+        }
+    }
+}
+
+// GameServer::builder().tick_ms(50).static_dir("web").build(), anything not
+// set keeps its GameServerConfig default
+#[derive(Default)]
+pub struct GameServerBuilder {
+    config: GameServerConfig,
+    observer: Option<Box<dyn ServerObserver>>,
+}
+
+impl GameServerBuilder {
+    pub fn tick_ms(mut self, tick_ms: u64) -> Self {
+        self.config.min_tick_ms = tick_ms.max(1);
+        self
+    }
+
+    pub fn default_interval(mut self, interval: u16) -> Self {
+        self.config.default_interval = interval;
+        self
+    }
+
+    pub fn min_board_size(mut self, width: i32, height: i32) -> Self {
+        self.config.min_board_width = width;
+        self.config.min_board_height = height;
+        self
+    }
+
+    pub fn max_interval(mut self, interval: u16) -> Self {
+        self.config.max_interval = interval;
+        self
+    }
+
+    pub fn max_food_value(mut self, max_food_value: i32) -> Self {
+        self.config.max_food_value = max_food_value;
+        self
+    }
+
+    pub fn max_board_size(mut self, width: i32, height: i32) -> Self {
+        self.config.max_board_width = width;
+        self.config.max_board_height = height;
+        self
+    }
+
+    pub fn max_game_duration(mut self, duration: Duration) -> Self {
+        self.config.max_game_duration = Some(duration);
+        self
+    }
+
+    pub fn finished_game_grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.finished_game_grace_period = grace_period;
+        self
+    }
+
+    pub fn highscore_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.highscore_file = path.into();
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.config.ping_interval = interval;
+        self
+    }
+
+    pub fn max_messages_per_tick(mut self, max_messages: u32) -> Self {
+        self.config.max_messages_per_tick = max_messages;
+        self
+    }
+
+    pub fn max_inputs_per_second(mut self, max_inputs: f64) -> Self {
+        self.config.max_inputs_per_second = max_inputs;
+        self
+    }
+
+    pub fn static_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.static_dir = path.into();
+        self
+    }
+
+    pub fn index_file(mut self, file: &str) -> Self {
+        self.config.index_file = file.to_string();
+        self
+    }
+
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.config.session_timeout = timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn max_queued_frames(mut self, max_queued_frames: usize) -> Self {
+        self.config.max_queued_frames = max_queued_frames;
+        self
+    }
+
+    pub fn max_players(mut self, max_players: usize) -> Self {
+        self.config.max_players = max_players;
+        self
+    }
+
+    pub fn max_username_len(mut self, max_username_len: usize) -> Self {
+        self.config.max_username_len = max_username_len;
+        self
+    }
+
+    pub fn max_chat_len(mut self, max_chat_len: usize) -> Self {
+        self.config.max_chat_len = max_chat_len;
+        self
+    }
+
+    pub fn allowed_origin(mut self, origin: &str) -> Self {
+        self.config.allowed_origins.push(origin.to_string());
+        self
+    }
+
+    // On top of the default snake-v1
+    pub fn subprotocol(mut self, subprotocol: &str) -> Self {
+        self.config.subprotocols.push(subprotocol.to_string());
+        self
+    }
+
+    pub fn score_submission(mut self, enabled: bool) -> Self {
+        self.config.score_submission = enabled;
+        self
+    }
+
+    pub fn observer(mut self, observer: impl ServerObserver + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    pub fn build(self) -> GameServer {
+        let mut server = GameServer::with_config(self.config);
+        if let Some(observer) = self.observer {
+            server.observer = observer;
+        }
+        server
+    }
+}
+
+// ============================================================================
+// OBSERVADOR DE EVENTOS
+// ============================================================================
+
+// For embedders that want to know what's going on in the server. Called from
+// inside the event loop, so keep these quick, everything is a no-op unless
+// overridden
+pub trait ServerObserver: Send + Sync {
+    fn on_connect(&self, _client_id: &str) {}
+    fn on_disconnect(&self, _client_id: &str) {}
+    fn on_join(&self, _client_id: &str, _game_id: &str) {}
+    // score is the best one among the snakes of that game
+    fn on_game_over(&self, _game_id: &str, _score: u32) {}
+    fn on_high_score(&self, _entry: &HighScoreEntry) {}
+}
+
+pub struct NoopObserver;
+impl ServerObserver for NoopObserver {}
 
 // ============================================================================
 // SERVIDOR DE JOGOS ASSÍNCRONO
@@ -22,26 +275,185 @@ pub struct GameServer {
     tx: UnboundedSender<GameEvent>,
     rx: UnboundedReceiver<GameEvent>,
     interval_buffer : HashMap<String, i32>,
-    high_scores: Vec<HighScoreEntry>,
+    high_scores: HighScoreBoards,
+    high_scores_dirty: bool, // changed since they were last saved
+    high_scores_saved_at: Instant,
+    high_scores_write: Option<JoinHandle<()>>,
+    game_started_at: HashMap<String, Instant>, // game_id -> when it (re)started
+    game_finished_at: HashMap<String, Instant>, // game_id -> when it got over
+    sessions: HashMap<String, DroppedSession>, // session token -> what a dropped client left behind
+    // counters for /metrics, since the server started
+    games_finished: u64,
+    ticks: u64,
+    observer: Box<dyn ServerObserver>,
+    gzip_cache: Arc<GzipCache>,
+    config: GameServerConfig,
 }
 
 pub struct ClientConnection {
-    id: String,
+    id: String, // stable for the whole connection, never reused
+    peer_addr: Option<String>, // just for the logs
     game_id: Option<String>,
     websocket: bool,
-    stream: OwnedWriteHalf,
+    stream: Option<Box<dyn AsyncWrite + Unpin + Send>>, // until start_writer takes it over
+    outbound: Option<mpsc::Sender<Outbound>>, // for the writer task to put on the socket
+    writer: Option<JoinHandle<()>>,
+    send_failed: bool, // outbound queue overflowed or the socket broke, it gets kicked
     username: Option<String>,
+    last_ping: Instant, // last time we pinged it
+    last_seen: Instant, // last time anything came from this client
+    message_allowance: u32, // game messages it can still send this tick
+    rate_limited: bool, // already told it to slow down this tick
+    input_rate: f64, // inputs per second, decaying average over INPUT_RATE_WINDOW_SECS
+    last_input: Instant,
+    suspect: bool, // inputs came too fast for a human, its scores aren't recorded
+    delta_updates: bool, // wants game_delta instead of game_state messages
+    binary_updates: bool, // wants the game state as binary frames (GameState::to_binary)
+    summary_updates: bool, // wants game_summary instead, no bodies
+    needs_full_state: bool, // its copy of the game is missing or outdated
+    spectator: bool, // just watching game_id, has no snake there
+    session_token: String,
 }
 impl ClientConnection {
-    pub fn new(id: &str, stream: OwnedWriteHalf ) -> Self {
+    pub fn new(id: &str, stream: impl AsyncWrite + Unpin + Send + 'static) -> Self {
         ClientConnection {
             id: id.to_string(),
+            peer_addr: None,
             websocket: false,
-            stream: stream,
+            stream: Some(Box::new(stream)),
+            outbound: None,
+            writer: None,
+            send_failed: false,
             username: None,
             game_id: None,
+            last_ping: Instant::now(),
+            last_seen: Instant::now(),
+            message_allowance: 0,
+            rate_limited: false,
+            input_rate: 0.0,
+            last_input: Instant::now(),
+            suspect: false,
+            delta_updates: false,
+            binary_updates: false,
+            summary_updates: false,
+            needs_full_state: true,
+            spectator: false,
+            session_token: new_session_token(),
+        }
+    }
+
+    pub fn with_peer_addr(mut self, peer_addr: &str) -> Self {
+        self.peer_addr = Some(peer_addr.to_string());
+        self
+    }
+
+    // Hands the socket over to a task of its own, so a slow client only
+    // ever holds up itself. Once the connection is dropped the writer
+    // flushes what is left and closes the socket
+    fn start_writer(&mut self, queue_len: usize) {
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        let (tx, mut rx) = mpsc::channel::<Outbound>(queue_len.max(1));
+        self.writer = Some(tokio::spawn(async move {
+            while let Some(outbound) = rx.recv().await {
+                let written = match outbound {
+                    Outbound::Bytes(bytes) => stream.write_all(&bytes).await,
+                    Outbound::Chunks(chunks) => write_chunks(&mut stream, chunks).await,
+                    // waits its turn here, so responses still go out in order
+                    Outbound::Pending(response) => match response.await {
+                        Ok(mut response) => {
+                            let chunks = response.take_chunks();
+                            match (stream.write_all(&response.as_bytes()).await, chunks) {
+                                (Ok(()), Some(chunks)) => write_chunks(&mut stream, chunks).await,
+                                (written, _) => written,
+                            }
+                        },
+                        Err(_) => break, // whatever was making it panicked
+                    },
+                };
+                if written.is_err() {
+                    break;
+                }
+            }
+            let _ = stream.shutdown().await;
+        }));
+        self.outbound = Some(tx);
+    }
+
+    // Folds one more input into input_rate, true if it just got over `max_rate`
+    fn count_input(&mut self, max_rate: f64) -> bool {
+        let elapsed = self.last_input.elapsed().as_secs_f64();
+        self.last_input = Instant::now();
+        self.input_rate = self.input_rate * (-elapsed / INPUT_RATE_WINDOW_SECS).exp() + 1.0 / INPUT_RATE_WINDOW_SECS;
+        if self.input_rate > max_rate && !self.suspect {
+            self.suspect = true;
+            return true;
+        }
+        false
+    }
+
+    // What it gets of its game this time, deltas only if there's one to give
+    fn state_encoding(&self, delta_available: bool) -> StateEncoding {
+        if self.summary_updates {
+            StateEncoding::Summary
+        } else if self.binary_updates {
+            StateEncoding::Binary
+        } else if self.delta_updates && !self.needs_full_state && delta_available {
+            StateEncoding::Delta
+        } else {
+            StateEncoding::Json
+        }
+    }
+
+    // Never waits on the socket: false if the bytes couldn't be queued
+    fn enqueue(&mut self, bytes: Vec<u8>) -> bool {
+        self.enqueue_outbound(Outbound::Bytes(bytes))
+    }
+
+    fn enqueue_outbound(&mut self, outbound: Outbound) -> bool {
+        let queued = self.outbound.as_ref().is_some_and(|tx| tx.try_send(outbound).is_ok());
+        if !queued {
+            self.send_failed = true;
         }
+        queued
+    }
+}
+
+// What the writer task puts on the socket
+enum Outbound {
+    Bytes(Vec<u8>),
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>), // a chunked http body, framed by the writer as it goes
+    Pending(JoinHandle<HttpResponse>), // a response still being made off the game loop (e.g. files)
+}
+
+// An empty chunk would read as the last one, so those are skipped
+async fn write_chunks(stream: &mut (impl AsyncWrite + Unpin), chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>) -> std::io::Result<()> {
+    for chunk in chunks.filter(|chunk| !chunk.is_empty()) {
+        stream.write_all(&HttpResponse::chunk_frame(&chunk)).await?;
     }
+    stream.write_all(HttpResponse::LAST_CHUNK).await
+}
+
+// How a client gets the game it is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StateEncoding {
+    Json, // the whole game_state
+    Delta, // game_delta, what changed since the previous tick
+    Binary, // the whole state, GameState::to_binary
+    Summary, // game_summary, GameState::summary
+}
+
+// Enough to put a client that lost its connection back where it was
+struct DroppedSession {
+    game_id: Option<String>,
+    snake_id: Option<String>, // its snake, kept in game_id under the old client id until it comes back
+    username: Option<String>,
+    delta_updates: bool,
+    binary_updates: bool,
+    summary_updates: bool,
+    suspect: bool,
+    dropped_at: Instant,
 }
 
 enum GameEvent {
@@ -50,98 +462,116 @@ enum GameEvent {
     GameTick,
 }
 
+// Cloneable handle to push connections and client messages into a running
+// GameServer, so it can be fed by something other than its own TCP listener
+#[derive(Clone)]
+pub struct GameEventSender {
+    tx: UnboundedSender<GameEvent>,
+}
+
+impl GameEventSender {
+    pub fn new_connection(&self, connection: ClientConnection) -> Result<(), Error> {
+        self.send(GameEvent::NewConnection(connection))
+    }
+
+    pub fn client_input(&self, client_id: &str, message: ClientMessage) -> Result<(), Error> {
+        self.send(GameEvent::ClientInput(client_id.to_string(), message))
+    }
+
+    fn send(&self, event: GameEvent) -> Result<(), Error> {
+        self.tx.send(event).map_err(|_| Error::new(
+            ErrorKind::BrokenPipe,
+            "Game server is not running anymore",
+        ))
+    }
+}
+
+impl Default for GameServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GameServer {
     pub fn new() -> Self {
+        Self::with_config(GameServerConfig::default())
+    }
+
+    pub fn builder() -> GameServerBuilder {
+        GameServerBuilder::default()
+    }
+
+    pub fn with_config(config: GameServerConfig) -> Self {
         let (tx, rx) = mpsc::unbounded_channel::<GameEvent>();
+        // tokio's interval panics on a zero period
+        let config = GameServerConfig { min_tick_ms: config.min_tick_ms.max(1), ..config };
         
         GameServer {
             games: HashMap::new(),
             clients: HashMap::new(),
-            tx: tx,
-            rx: rx,
+            tx,
+            rx,
             interval_buffer: HashMap::new(),
-            high_scores: Vec::new(),
+            high_scores: load_high_scores(&config.highscore_file),
+            high_scores_dirty: false,
+            high_scores_saved_at: Instant::now(),
+            high_scores_write: None,
+            game_started_at: HashMap::new(),
+            game_finished_at: HashMap::new(),
+            sessions: HashMap::new(),
+            games_finished: 0,
+            ticks: 0,
+            observer: Box::new(NoopObserver),
+            gzip_cache: Arc::new(GzipCache::default()),
+            config,
         }
     }
 
+    pub fn with_observer(mut self, observer: impl ServerObserver + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    pub fn event_sender(&self) -> GameEventSender {
+        GameEventSender { tx: self.tx.clone() }
+    }
+
+    // Serves until ctrl-c, then closes every connection and saves high scores
     pub async fn run(&mut self, address: String) {
         // INPUT IO
-        let input_tx = self.tx.clone();
-        tokio::spawn(  async move {
+        let input_tx = self.event_sender();
+        let listener_task = tokio::spawn(  async move {
             let tcp_listener = tokio::net::TcpListener::bind(address.clone())
                 .await
-                .expect(
-                    format!("Error binding to {}", address).as_str()
-                );
-            println!("Web server listening from {}", address);
+                .unwrap_or_else(|_| panic!("Error binding to {}", address));
+            info!(%address, "Web server listening");
+            let mut next_client_id: u64 = 1;
             loop {
                 let result = tcp_listener.accept().await;
                 match result {
                     Ok((tcp_stream, addr)) => {
-                        println!("New connection from {}", addr.clone());
-                        let client_tx = input_tx.clone();
-                        tokio::spawn(
-                            async move {
-                                let (mut tcp_rx, tcp_tx) = tcp_stream.into_split();
-                                let _ = client_tx.send(
-                                    GameEvent::NewConnection(
-                                        ClientConnection::new(
-                                            addr.to_string().as_str(),
-                                            tcp_tx,
-                                        ),
-                                    ),
-                                );
-                                let mut buff = [0; 2048];
-                                let mut vec_buff = Vec::new();
-                                loop {
-                                    match tcp_rx.read(&mut buff).await {
-                                        Err(_) => {
-                                            let _ = client_tx.send(
-                                                GameEvent::ClientInput(
-                                                    addr.to_string(),
-                                                    ClientMessage::Invalid,
-                                                ),
-                                            );
-                                        },
-                                        Ok(n) => {
-                                            vec_buff.append(&mut buff[0..n].to_vec());
-                                            if vec_buff.len() > MAX_HTTP_BUFFER_LEN {
-                                                println!("Buffer from {} is huge (>{}), clearing it", addr.to_string(), MAX_HTTP_BUFFER_LEN);
-                                                vec_buff.clear();
-                                            }
-                                            if n == 0 {
-                                                let _ = client_tx.send(
-                                                    GameEvent::ClientInput(
-                                                        addr.to_string(),
-                                                        ClientMessage::Disconnect,
-                                                    ),
-                                                );
-                                                break; // to end the task
-                                            } else {
-                                                let parsed_input = parse_client_message(&mut vec_buff);
-                                                let _ = client_tx.send(
-                                                    GameEvent::ClientInput(
-                                                        addr.to_string(),
-                                                        parsed_input,
-                                                    ),
-                                                );
-                                            }
-                                        },
-                                    }
-                                    
-                                }      
-                            }
+                        // the address may be reused later on (or shared, behind
+                        // a NAT), so clients get ids of their own
+                        let client_id = next_client_id.to_string();
+                        next_client_id += 1;
+                        info!(%client_id, peer_addr = %addr, "New connection");
+                        let (tcp_rx, tcp_tx) = tcp_stream.into_split();
+                        let _ = input_tx.new_connection(
+                            ClientConnection::new(&client_id, tcp_tx).with_peer_addr(&addr.to_string()),
                         );
+                        // the write half gets a task of its own once the game
+                        // loop takes the connection in (see start_writer)
+                        tokio::spawn(read_client(client_id, tcp_rx, input_tx.clone()));
                     },
-                    Err(err) => println!("Error on tcp_listener: {err}"),
+                    Err(err) => error!(error = %err, "Error on tcp_listener"),
                 }
             }
         });
 
         // GAME UPDATE TIMER TICK
         let tick_tx = self.tx.clone();
-        let mut game_timer = tokio::time::interval(Duration::from_millis(MINIMUM_TICK as u64));
-        tokio::spawn( async move {
+        let mut game_timer = tokio::time::interval(Duration::from_millis(self.config.min_tick_ms));
+        let timer_task = tokio::spawn( async move {
             loop {
                 game_timer.tick().await;
                 let _ = tick_tx.send(GameEvent::GameTick);
@@ -149,67 +579,253 @@ impl GameServer {
         });
 
         // Receiving events on a loop
+        let shutdown_signal = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown_signal);
         loop {
-            while let Some(event) = self.rx.recv().await {
-                self.handle_io_event(event).await;
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(event) => self.handle_io_event(event).await,
+                    // every sender is gone, nothing will ever come in again
+                    None => {
+                        info!("Event channel closed, shutting down");
+                        break;
+                    },
+                },
+                _ = &mut shutdown_signal => {
+                    info!("Shutting down");
+                    break;
+                },
             }
         }
-        
+
+        listener_task.abort();
+        timer_task.abort();
+        self.shutdown().await;
+    }
+
+    async fn shutdown(&mut self) {
+        let mut writers = Vec::new();
+        for client in self.clients.values_mut() {
+            if client.websocket {
+                client.enqueue(WebSocketFrame::control(WebSocketFrame::OPCODE_CLOSE, Vec::new()));
+            }
+            writers.extend(client.writer.take());
+        }
+        self.clients.clear();
+        // whatever is still pending goes to disk before we're gone
+        if let Some(write) = self.high_scores_write.take() {
+            let _ = write.await;
+        }
+        if self.high_scores_dirty {
+            let _ = self.write_high_scores().await;
+        }
+        // a moment for the writers to flush, nobody waits on a stuck socket
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            for writer in writers {
+                let _ = writer.await;
+            }
+        }).await;
     }
 
     async fn handle_io_event(&mut self, ev: GameEvent) {
         match ev {
             GameEvent::ClientInput(clientid, client_message) => {
+                match self.clients.get_mut(&clientid) {
+                    Some(client) => client.last_seen = Instant::now(),
+                    // already gone (e.g. sent a close frame), its socket
+                    // reader just hasn't noticed yet
+                    None => return,
+                }
                 match client_message {
                     ClientMessage::ClientGameMessage(client_game_message) => {
-                        self.handle_client_game_message(clientid, client_game_message).await;
+                        let client = self.clients.get_mut(&clientid).unwrap();
+                        if client.message_allowance > 0 {
+                            client.message_allowance -= 1;
+                            self.handle_client_game_message(clientid, client_game_message).await;
+                        } else if !client.rate_limited {
+                            client.rate_limited = true;
+                            let _ = self.send_websocket_response(
+                                &clientid,
+                                &ServerMessage::error("Too many messages, slow down"),
+                            ).await;
+                        }
                     },
                     ClientMessage::HttpRequest(http_request) => {
                         self.handle_client_http_request(
                             clientid, &http_request
                         ).await;
                     },
-                    ClientMessage::Invalid => {
-                        println!("Client {} sent an invalid message", clientid);
+                    ClientMessage::Invalid(reason) => {
+                        let peer_addr = self.clients[&clientid].peer_addr.clone().unwrap_or("unknown address".to_string());
+                        warn!(client_id = %clientid, %peer_addr, %reason, "Client sent an invalid message");
+                        // let it know what went wrong, when there's a way to tell it
+                        let websocket = self.clients[&clientid].websocket;
+                        match reason {
+                            InvalidReason::BadJson(_) | InvalidReason::NonUtf8Payload | InvalidReason::BadFrame(_) if websocket => {
+                                let _ = self.send_websocket_response(
+                                    &clientid,
+                                    &ServerMessage::error(&reason.to_string()),
+                                ).await;
+                            },
+                            InvalidReason::MalformedHttp | InvalidReason::BodyTooLarge if !websocket => {
+                                self.send_http_response(&clientid, HttpResponse::bad_request()).await;
+                            },
+                            _ => {},
+                        }
                     },
                     ClientMessage::Disconnect => {
-                        self.clients.remove(&clientid);
+                        self.disconnect_client(&clientid).await;
                     },
                     ClientMessage::Incomplete => {
                     },
+                    ClientMessage::Ping(payload) => {
+                        if let Some(client) = self.clients.get_mut(&clientid) {
+                            client.enqueue(WebSocketFrame::control(WebSocketFrame::OPCODE_PONG, payload));
+                        }
+                    },
+                    ClientMessage::Pong => {
+                        // solicited or not, a pong just tells us the client is
+                        // alive, and last_seen already took note of that
+                    },
                 }
             },
-            GameEvent::NewConnection(client_connection) => {
+            GameEvent::NewConnection(mut client_connection) => {
+                if self.config.max_connections.is_some_and(|max| self.clients.len() >= max) {
+                    warn!(client_id = %client_connection.id, "Too many connections, turning this one away");
+                    if let Some(stream) = client_connection.stream.as_mut() {
+                        let _ = stream.write_all(&HttpResponse::service_unavailable().as_bytes()).await;
+                        let _ = stream.shutdown().await;
+                    }
+                    return;
+                }
+                client_connection.start_writer(self.config.max_queued_frames);
+                client_connection.message_allowance = self.config.max_messages_per_tick;
+                self.observer.on_connect(&client_connection.id);
                 self.clients.insert(
                     client_connection.id.clone(),
                     client_connection,
                 );
             },
             GameEvent::GameTick => {
+                self.ticks += 1;
+                let idle_clientids: Vec<String> = self.clients
+                    .values()
+                    .filter(|client| client.last_seen.elapsed() >= self.config.idle_timeout)
+                    .map(|client| client.id.clone())
+                    .collect();
+                for clientid in idle_clientids {
+                    info!(client_id = %clientid, "Kicking client, idle for too long");
+                    self.disconnect_client(&clientid).await;
+                }
+                let stuck_clientids: Vec<String> = self.clients
+                    .values()
+                    .filter(|client| client.send_failed)
+                    .map(|client| client.id.clone())
+                    .collect();
+                for clientid in stuck_clientids {
+                    info!(client_id = %clientid, "Kicking client, it isn't keeping up with what we send");
+                    self.disconnect_client(&clientid).await;
+                }
+                // spectators and players going straight don't send anything,
+                // make them answer something
+                let ping_interval = self.config.ping_interval;
+                for client in self.clients.values_mut().filter(|client| client.websocket) {
+                    if client.last_seen.elapsed() >= ping_interval && client.last_ping.elapsed() >= ping_interval {
+                        client.last_ping = Instant::now();
+                        client.enqueue(WebSocketFrame::control(WebSocketFrame::OPCODE_PING, Vec::new()));
+                    }
+                }
+                let session_timeout = self.config.session_timeout;
+                let expired_tokens: Vec<String> = self.sessions
+                    .iter()
+                    .filter(|(_, session)| session.dropped_at.elapsed() >= session_timeout)
+                    .map(|(token, _)| token.clone())
+                    .collect();
+                for token in expired_tokens {
+                    let session = self.sessions.remove(&token).unwrap();
+                    // it's not coming back, and neither is its snake
+                    if let (Some(game_id), Some(snake_id)) = (session.game_id, session.snake_id) {
+                        if let Some(game) = self.games.get_mut(&game_id) {
+                            game.remove_snake(&snake_id);
+                        }
+                        self.drop_game_if_abandoned(&game_id);
+                    }
+                }
+                // games whose players all dropped wait for them to come back
+                let playing_gameids: HashSet<&String> = self.clients
+                    .values()
+                    .filter(|client| !client.spectator)
+                    .filter_map(|client| client.game_id.as_ref())
+                    .collect();
+                let paused_gameids: HashSet<String> = self.sessions
+                    .values()
+                    .filter(|session| session.snake_id.is_some())
+                    .filter_map(|session| session.game_id.clone())
+                    .filter(|game_id| !playing_gameids.contains(game_id))
+                    .collect();
+                for client in self.clients.values_mut() {
+                    client.message_allowance = self.config.max_messages_per_tick;
+                    client.rate_limited = false;
+                }
                 let mut updated_gameids = HashSet::new();
+                let mut food_frames: HashMap<String, Vec<u8>> = HashMap::new(); // game_id -> food_eaten
+                let mut game_over_frames: HashMap<String, Vec<u8>> = HashMap::new(); // game_id -> game_over, versus only
                 for (gameid, game) in self.games.iter_mut() {
+                    if paused_gameids.contains(gameid) {
+                        continue;
+                    }
                     if !self.interval_buffer.contains_key(gameid) {
                         self.interval_buffer.insert(gameid.clone(), 0);
                     }
                     self.interval_buffer
                             .entry(gameid.clone())
                             .and_modify(
-                                |old| { *old -= MINIMUM_TICK }
+                                |old| { *old -= self.config.min_tick_ms as i32 }
                             ).or_insert(0);
-                    if *self.interval_buffer.get(gameid).unwrap() < 0 {
-                        let game_over = game.game_over;
-                        game.update();
-                        if game.game_over && !game_over {
+                    let out_of_time = game.advance_clock(self.config.min_tick_ms);
+                    if out_of_time {
+                        info!(game_id = %gameid, "Game ran out of time");
+                    }
+                    let expired = has_expired(game, self.game_started_at.get(gameid).copied(), self.config.max_game_duration, Instant::now());
+                    if expired {
+                        info!(game_id = %gameid, "Game reached the maximum duration, ending it");
+                        game.game_over = true;
+                    }
+                    if out_of_time || expired || *self.interval_buffer.get(gameid).unwrap() < 0 {
+                        let outcome = game.update();
+                        if let Some(snake) = outcome.eater.as_ref().and_then(|id| game.snakes.get(id)) {
+                            let food_eaten = ServerMessage::FoodEaten {
+                                position: snake.head(),
+                                new_score: snake.score,
+                            };
+                            if let Ok(json) = serde_json::to_string(&food_eaten) {
+                                food_frames.insert(gameid.clone(), WebSocketFrame::to_websocket(json.into_bytes()));
+                            }
+                        }
+                        if outcome.ended || out_of_time || expired {
                             // game has done now
-                            // lets register high scores
-                            for (_,client) in self.clients.iter() {
-                                if let Some(username) = &client.username {
-                                    self.high_scores.push(
-                                        HighScoreEntry {
-                                            username: username.to_string(),
-                                            score: game.score as u32
-                                        }
-                                    );
+                            self.games_finished += 1;
+                            self.observer.on_game_over(
+                                gameid,
+                                game.snakes.values().map(|snake| snake.score).max().unwrap_or(0).max(0) as u32,
+                            );
+                            if game.versus {
+                                let game_over = ServerMessage::GameOver { winner: game.winner.clone() };
+                                if let Ok(json) = serde_json::to_string(&game_over) {
+                                    game_over_frames.insert(gameid.clone(), WebSocketFrame::to_websocket(json.into_bytes()));
+                                }
+                            }
+                            // lets register high scores, only for who was in this game
+                            let board = game.board_key();
+                            for (clientid, client) in self.clients.iter().filter(|(_, client)| client.game_id.as_ref() == Some(gameid) && !client.spectator && !client.suspect) {
+                                if let (Some(username), Some(snake)) = (&client.username, game.snakes.get(clientid)) {
+                                    let entry = HighScoreEntry {
+                                        username: username.to_string(),
+                                        score: snake.score.max(0) as u32,
+                                        won: (game.won && snake.alive) || game.winner.as_ref() == Some(clientid),
+                                    };
+                                    self.observer.on_high_score(&entry);
+                                    self.high_scores_dirty |= self.high_scores.push(Some(&board), entry);
                                 }
                             }
                         }
@@ -217,46 +833,87 @@ impl GameServer {
                         self.interval_buffer
                             .entry(gameid.clone())
                             .and_modify(
-                                |old| { *old += game.interval as i32 }
+                                |old| { *old += game.effective_interval() as i32 }
                             );
                     }
+                    if game.game_over {
+                        self.game_finished_at.entry(gameid.clone()).or_insert_with(Instant::now);
+                    } else {
+                        self.game_finished_at.remove(gameid);
+                    }
                 }
-                self.interval_buffer.retain(
-                    |k, _| { self.games.contains_key(k)}
-                );
-                let messages_to_send: Vec<(String, Vec<ServerMessage>)> = self.clients
+                let stale_gameids: Vec<String> = self.game_finished_at
                     .iter()
-                    .filter_map(|(clientid, client)| {
-                        match &client.game_id {
-                            Some(gameid) if updated_gameids.contains(gameid) => {
-                                if let Some(gamestate) = self.games.get_mut(gameid) {
-                                    if gamestate.game_over && gamestate.already_sent_gameovers_to.contains(clientid) {
-                                        None
-                                    } else {
-                                        let mut ret = Vec::new();
-                                        if gamestate.game_over {
-                                            gamestate.already_sent_gameovers_to.insert(clientid.clone());
-                                            ret.push(ServerMessage::HighScores(HighScores::from_vec(&mut self.high_scores)));
-                                        }
-                                        ret.push(ServerMessage::game_state(gamestate.clone()));
-                                        Some((clientid.clone(), ret))   
-                                    }
-                                } else {
-                                    None
-                                }
-                            },
-                            Some(_) => None,
-                            None => None,
-                        }
+                    .filter(|(gameid, finished_at)| {
+                        finished_at.elapsed() >= self.config.finished_game_grace_period
+                            && !self.is_game_in_use(gameid)
                     })
+                    .map(|(gameid, _)| gameid.clone())
                     .collect();
-                for (client_id, messages) in messages_to_send {
-                    for message in messages {
-                        if let Err(e) = self.send_websocket_response(&client_id, &message).await {
-                            eprintln!("Failed to send to {}: {}", client_id, e);
+                for gameid in stale_gameids {
+                    self.remove_game(&gameid);
+                }
+                self.save_high_scores();
+                // (client_id, game_id, whether high scores go first, how it gets the game)
+                let mut to_send: Vec<(String, String, bool, StateEncoding)> = Vec::new();
+                for (clientid, client) in self.clients.iter_mut() {
+                    match &client.game_id {
+                        Some(gameid) if updated_gameids.contains(gameid) => {
+                            if let Some(gamestate) = self.games.get_mut(gameid) {
+                                if gamestate.game_over && gamestate.already_sent_gameovers_to.contains(clientid) {
+                                    continue;
+                                }
+                                if gamestate.game_over {
+                                    gamestate.already_sent_gameovers_to.insert(clientid.clone());
+                                }
+                                let encoding = client.state_encoding(gamestate.last_delta().is_some());
+                                client.needs_full_state = false;
+                                to_send.push((clientid.clone(), gameid.clone(), gamestate.game_over, encoding));
+                            }
+                        },
+                        Some(_) => {},
+                        None => {},
+                    }
+                }
+                // every client of a game gets the very same bytes,
+                // so each game state (or delta) is serialized only once
+                let mut frames: HashMap<(String, StateEncoding), Vec<u8>> = HashMap::new();
+                for (_, gameid, _, encoding) in to_send.iter() {
+                    let key = (gameid.clone(), *encoding);
+                    if frames.contains_key(&key) {
+                        continue;
+                    }
+                    if let Some(frame) = self.games.get(gameid).and_then(|gamestate| state_frame(gameid, gamestate, *encoding)) {
+                        frames.insert(key, frame);
+                    }
+                }
+                let mut highscores_frames: HashMap<String, Vec<u8>> = HashMap::new(); // game_id -> its board
+                // clients we couldn't write to: nothing more goes their way,
+                // they're dropped once everyone else got theirs
+                let mut failed_clientids: HashSet<String> = HashSet::new();
+                for (client_id, gameid, with_highscores, encoding) in to_send {
+                    let mut frames_for_client = Vec::new();
+                    if with_highscores {
+                        let board = self.games.get(&gameid).map(|game| game.board_key());
+                        let frame = highscores_frames.entry(gameid.clone()).or_insert_with(|| {
+                            let highscores = ServerMessage::HighScores(HighScores::from_board(&self.high_scores, board.as_deref()));
+                            WebSocketFrame::to_websocket(serde_json::to_vec(&highscores).unwrap_or_default())
+                        });
+                        frames_for_client.push(&*frame);
+                    }
+                    let state_frame = frames.get(&(gameid.clone(), encoding));
+                    frames_for_client.extend([state_frame, food_frames.get(&gameid), game_over_frames.get(&gameid)].into_iter().flatten());
+                    for frame in frames_for_client {
+                        if let Err(e) = self.send_websocket_frame(&client_id, frame).await {
+                            warn!(%client_id, error = %e, "Failed to send");
+                            failed_clientids.insert(client_id);
+                            break;
                         }
                     }
                 }
+                for clientid in failed_clientids {
+                    self.disconnect_client(&clientid).await;
+                }
             },
         }
     }
@@ -264,80 +921,391 @@ impl GameServer {
     async fn handle_client_http_request(&mut self, clientid: String, req: &HttpRequest) {
         let client = self.clients.get_mut(&clientid).unwrap();
         if req.is_websocket_handshake() {
-            client.websocket = true;
+            let response = HttpResponse::websocket_handshake(req, &self.config.subprotocols);
+            let upgraded = response.status_code() == 101;
+            client.websocket = upgraded;
             self.send_http_response(
                 &clientid, 
-                HttpResponse::websocket_handshake(req),
+                response,
             ).await;
-            let _ = self.send_websocket_highscores(&clientid).await;
+            if upgraded {
+                let _ = self.send_websocket_highscores(&clientid).await;
+            }
         } else {
-            // all the proper router stuff goes here
-            // we only have index.html so
-            if req.method == HttpMethod::GET {
-                let (_, mut filepath) = req.path.split_once('/').unwrap();
-                if filepath.len() == 0 {
-                    filepath = "index.html";
-                }
-                self.send_http_response(
-                    clientid.as_str(),
-                    HttpResponse::file_content(
-                        format!("public/{}", filepath).as_str()
-                    ),
-                ).await;
-            } else {
-                self.send_http_response(
-                    clientid.as_str(),
-                    HttpResponse::not_found()
-                ).await;
+            let cors_origin = req.headers
+                .get("origin")
+                .filter(|origin| self.config.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == *origin))
+                .cloned();
+            let keep_alive = req.keep_alive();
+            let finish = move |response: HttpResponse| {
+                let response = match &cors_origin {
+                    Some(origin) => response.with_cors(origin),
+                    None => response,
+                };
+                response.with_keep_alive(keep_alive)
+            };
+            match self.route_http_request(&clientid, req) {
+                Some(response) => self.send_http_response(&clientid, finish(response)).await,
+                // static files: disk reads and gzip happen on a blocking
+                // thread, the writer sends the response once it's ready
+                None => {
+                    let static_dir = self.config.static_dir.clone();
+                    let index_file = self.config.index_file.clone();
+                    let gzip_cache = self.gzip_cache.clone();
+                    let req = req.clone();
+                    let response = tokio::task::spawn_blocking(move || {
+                        finish(static_file_response(&static_dir, &index_file, &req, &gzip_cache))
+                    });
+                    let client = self.clients.get_mut(&clientid).unwrap();
+                    client.enqueue_outbound(Outbound::Pending(response));
+                },
+            }
+            if !keep_alive {
+                self.disconnect_client(&clientid).await;
+            }
+        }
+    }
+
+    // Everything but the static files, None for those. HEAD gets what GET
+    // would, minus the body
+    fn route_http_request(&mut self, clientid: &str, req: &HttpRequest) -> Option<HttpResponse> {
+        let get = matches!(req.method, HttpMethod::GET | HttpMethod::HEAD);
+        let response = if get && req.path == "/highscores" {
+            let limit = match req.query.get("limit").map(|limit| limit.parse::<usize>()) {
+                None => Some(10),
+                Some(Ok(limit)) => Some(limit),
+                Some(Err(_)) => None,
+            };
+            match limit {
+                Some(limit) => {
+                    let highscores = HighScores::top(&self.high_scores.board(None), limit);
+                    HttpResponse::json(serde_json::to_vec(&highscores).unwrap_or_default())
+                },
+                None => HttpResponse::bad_request(),
+            }
+        } else if req.method == HttpMethod::POST && req.path == "/highscore" && self.config.score_submission {
+            let entry = req.body
+                .as_deref()
+                .and_then(|body| serde_json::from_slice::<HighScoreEntry>(body).ok())
+                .and_then(|entry| Some(HighScoreEntry {
+                    username: self.validate_username(&entry.username).ok()?,
+                    ..entry
+                }));
+            match entry {
+                Some(entry) => {
+                    info!(client_id = %clientid, score = entry.score, username = %entry.username, "Score submitted");
+                    self.observer.on_high_score(&entry);
+                    self.high_scores_dirty |= self.high_scores.push(None, entry);
+                    let highscores = HighScores::top(&self.high_scores.board(None), 10);
+                    HttpResponse::json(serde_json::to_vec(&highscores).unwrap_or_default())
+                },
+                None => HttpResponse::bad_request(),
             }
+        } else if req.method == HttpMethod::OPTIONS {
+            HttpResponse::preflight(req)
+        } else if get && req.path == "/healthz" {
+            // for load balancers, no disk and no game state involved
+            HttpResponse::text("ok".to_string())
+        } else if let Some(game_id) = req.path.strip_prefix("/game/").filter(|_| get) {
+            // read-only peek at a game, for dashboards and the like
+            match self.games.get(game_id) {
+                Some(game) => HttpResponse::json(serde_json::to_vec(game).unwrap_or_default()),
+                None => HttpResponse::not_found(),
+            }
+        } else if get && req.path == "/metrics" {
+            HttpResponse::text(self.metrics())
+        } else if get {
+            return None;
+        } else {
+            HttpResponse::method_not_allowed()
+        };
+        match req.method {
+            HttpMethod::HEAD => Some(response.without_body()),
+            _ => Some(response),
         }
     }
 
+    // Prometheus text exposition format
+    fn metrics(&self) -> String {
+        let metrics: [(&str, &str, &str, u64); 5] = [
+            ("snake_connected_clients", "gauge", "Clients connected right now", self.clients.len() as u64),
+            ("snake_active_games", "gauge", "Games not over yet", self.games.values().filter(|game| !game.game_over).count() as u64),
+            ("snake_games_finished_total", "counter", "Games that got over", self.games_finished),
+            ("snake_high_scores", "gauge", "Entries kept over all high score boards", self.high_scores.len() as u64),
+            ("snake_ticks_total", "counter", "Game ticks processed", self.ticks),
+        ];
+        metrics.iter()
+            .map(|(name, kind, help, value)| format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"))
+            .collect()
+    }
+
     async fn handle_client_game_message(&mut self, clientid: String, msg: ClientGameMessage) {
+        if let ClientGameMessage::ListGames = msg {
+            let games = self.room_summaries();
+            let _ = self.send_websocket_response(&clientid, &ServerMessage::GameList { games }).await;
+            return;
+        }
+        if let ClientGameMessage::Hello { version } = msg {
+            if version != PROTOCOL_VERSION {
+                let _ = self.send_websocket_response(
+                    &clientid,
+                    &ServerMessage::error(&format!(
+                        "Protocol version {} is not supported, this server speaks version {}",
+                        version, PROTOCOL_VERSION,
+                    )),
+                ).await;
+                self.disconnect_client(&clientid).await;
+            }
+            return;
+        }
+        if let ClientGameMessage::Chat { text } = msg {
+            self.handle_chat(&clientid, text).await;
+            return;
+        }
+        let msg = match msg {
+            ClientGameMessage::Resume { token } => match self.resume_session(&clientid, token) {
+                // back to its game, as if joining it again
+                Ok(Some(game_id)) => {
+                    let client = &self.clients[&clientid];
+                    ClientGameMessage::JoinGame(JoinGame {
+                        game_id: Some(game_id),
+                        delta_updates: Some(client.delta_updates),
+                        binary: Some(client.binary_updates),
+                        summary: Some(client.summary_updates),
+                        ..Default::default()
+                    })
+                },
+                Ok(None) => {
+                    let _ = self.send_websocket_response(
+                        &clientid,
+                        &ServerMessage::error("Session resumed, but its game is gone"),
+                    ).await;
+                    return;
+                },
+                Err(error) => {
+                    let _ = self.send_websocket_response(&clientid, &ServerMessage::error(error)).await;
+                    return;
+                },
+            },
+            msg => msg,
+        };
+        let msg = match msg {
+            ClientGameMessage::Username { username } => match self.validate_username(&username) {
+                Ok(username) => ClientGameMessage::Username { username },
+                Err(error) => {
+                    let _ = self.send_websocket_response(&clientid, &ServerMessage::error(&error)).await;
+                    return;
+                },
+            },
+            msg => msg,
+        };
+        if let ClientGameMessage::Username { username } = &msg {
+            if self.is_username_taken(&clientid, username) {
+                let _ = self.send_websocket_response(&clientid, &ServerMessage::error("username taken")).await;
+                return;
+            }
+        }
+        let target_is_full = match &msg {
+            ClientGameMessage::JoinGame(JoinGame { game_id: Some(game_id), .. }) => self.is_game_full(game_id, &clientid),
+            ClientGameMessage::Spectate { game_id, .. } => self.is_game_full(game_id, &clientid),
+            _ => false,
+        };
         let client = self.clients.get_mut(&clientid).unwrap();
         let mut current_game : Option<&mut GameState> = None;
         if let Some(id) = &client.game_id {
             current_game = self.games.get_mut(id);
         }
+        let mut left_game_id : Option<String> = None;
+        let mut joined_game_id : Option<String> = None;
+        let mut refreshed_game_id : Option<String> = None; // its clients need the full state again
+        let mut reset_game_id : Option<String> = None;
         let client_response : Option<ServerMessage> = match (current_game, msg) {
             (_, ClientGameMessage::JoinGame(joingame)) => {
-                        if let Some(_id) = &client.game_id {
-                            // gotta leave
-                            client.game_id = None;
-                        };
-                        let new_game_id = rand::random::<u64>().to_string();
-                        client.game_id = Some(new_game_id.clone());
-                        self.games.insert(
-                            new_game_id,
-                            GameState::new(
-                                joingame.size.unwrap_or_default().width,
-                                joingame.size.unwrap_or_default().height,
-                            ),
-                        );
-                        Some(ServerMessage::Connected { client_id: clientid.clone() })
+                        match joingame.game_id {
+                            Some(id) if !self.games.contains_key(&id) => {
+                                Some(ServerMessage::error(&format!("Game {} not found", id)))
+                            },
+                            Some(id) if target_is_full => {
+                                Some(ServerMessage::error(&format!("Game {} is full", id)))
+                            },
+                            None if joingame.size.is_some_and(|size| size.width <= 0 || size.height <= 0) => {
+                                Some(ServerMessage::error("Board width and height must be positive"))
+                            },
+                            _ => {
+                                if let Some(old_id) = client.game_id.take() {
+                                    // gotta leave
+                                    if let Some(old_game) = self.games.get_mut(&old_id) {
+                                        old_game.remove_snake(&clientid);
+                                    }
+                                    left_game_id = Some(old_id);
+                                };
+                                let target_game_id = match joingame.game_id {
+                                    Some(id) => {
+                                        let game = self.games.get_mut(&id).unwrap();
+                                        // a resumed player already has its snake there
+                                        if game.snakes.contains_key(&clientid) || game.add_snake(&clientid) {
+                                            game.owner.get_or_insert_with(|| clientid.clone());
+                                            Some(id)
+                                        } else {
+                                            None
+                                        }
+                                    },
+                                    None => {
+                                        let new_game_id = rand::random::<u64>().to_string();
+                                        self.game_started_at.insert(new_game_id.clone(), Instant::now());
+                                        let size = joingame.size.unwrap_or_default();
+                                        let mut game = GameState::new(
+                                            size.width.min(self.config.max_board_width).max(self.config.min_board_width),
+                                            size.height.min(self.config.max_board_height).max(self.config.min_board_height),
+                                        )
+                                        .with_wall_mode(joingame.wall_mode.unwrap_or_default())
+                                        .with_snake_config(SnakeConfig {
+                                            length: joingame.snake_length.unwrap_or(SnakeConfig::default().length),
+                                            direction: joingame.start_direction.unwrap_or(SnakeConfig::default().direction),
+                                        });
+                                        let min_interval = self.config.min_tick_ms.min(u16::MAX as u64) as u16;
+                                        game.interval = self.config.default_interval.max(min_interval);
+                                        if let Some(speed_ramp) = joingame.speed_ramp {
+                                            // can't go any faster than the server ticks
+                                            game = game.with_speed_ramp(SpeedRamp {
+                                                start_interval: speed_ramp.start_interval.max(min_interval),
+                                                min_interval: speed_ramp.min_interval.max(min_interval),
+                                                ..speed_ramp
+                                            });
+                                        }
+                                        if joingame.food_value.is_some() || joingame.length_bonus.is_some() {
+                                            let food_value = joingame.food_value.unwrap_or(game.food_value).min(self.config.max_food_value).max(1);
+                                            game = game.with_food_value(food_value, joingame.length_bonus.unwrap_or(false));
+                                        }
+                                        if let Some(poison_chance) = joingame.poison_chance {
+                                            game = game.with_poison_chance(poison_chance);
+                                        }
+                                        if let Some(power_up_chance) = joingame.power_up_chance {
+                                            game = game.with_power_up_chance(power_up_chance);
+                                        }
+                                        let max_players = joingame.max_players.unwrap_or(self.config.max_players);
+                                        game = game.with_max_players(max_players.clamp(1, self.config.max_players.max(1)));
+                                        if let Some(versus) = joingame.versus {
+                                            game = game.with_versus(versus);
+                                        }
+                                        if let Some(time_limit_ms) = joingame.time_limit_ms {
+                                            game = game.with_time_limit(time_limit_ms);
+                                        }
+                                        if let Some(max_length) = joingame.max_length {
+                                            game = game.with_max_length(max_length);
+                                        }
+                                        if let Some(obstacles) = joingame.obstacles {
+                                            // leave most of the board walkable
+                                            let max_obstacles = (game.width * game.height / 4).max(0) as usize;
+                                            game = game.with_obstacles(obstacles.min(max_obstacles));
+                                        }
+                                        game.add_snake(&clientid);
+                                        game.owner = Some(clientid.clone());
+                                        self.games.insert(new_game_id.clone(), game);
+                                        Some(new_game_id)
+                                    },
+                                };
+                                match target_game_id {
+                                    Some(id) => {
+                                        client.game_id = Some(id.clone());
+                                        client.spectator = false;
+                                        client.delta_updates = joingame.delta_updates.unwrap_or(false);
+                                        client.binary_updates = joingame.binary.unwrap_or(false);
+                                        client.summary_updates = joingame.summary.unwrap_or(false);
+                                        if joingame.add_bot == Some(true) {
+                                            if let Some(bot_id) = self.games.get_mut(&id).and_then(|game| game.add_bot()) {
+                                                debug!(game_id = %id, %bot_id, "Bot joined");
+                                            }
+                                        }
+                                        // there's a new snake on that board
+                                        refreshed_game_id = Some(id.clone());
+                                        joined_game_id = Some(id);
+                                        Some(ServerMessage::connected(clientid.clone(), client.session_token.clone()))
+                                    },
+                                    None => Some(ServerMessage::error("No room left on that board")),
+                                }
+                            },
+                        }
                     },
+            (_, ClientGameMessage::Spectate { game_id, .. }) if target_is_full => {
+                Some(ServerMessage::error(&format!("Game {} is full", game_id)))
+            },
+            (_, ClientGameMessage::Spectate { game_id, summary }) => {
+                if self.games.contains_key(&game_id) {
+                    if let Some(old_id) = client.game_id.take() {
+                        if let Some(old_game) = self.games.get_mut(&old_id) {
+                            old_game.remove_snake(&clientid);
+                        }
+                        if old_id != game_id {
+                            left_game_id = Some(old_id);
+                        }
+                    }
+                    client.game_id = Some(game_id);
+                    client.spectator = true;
+                    client.summary_updates = summary.unwrap_or(false);
+                    client.needs_full_state = true;
+                    None
+                } else {
+                    Some(ServerMessage::error(&format!("Game {} not found", game_id)))
+                }
+            },
+            // spectators can look, not touch
+            (Some(_), ClientGameMessage::Input { .. } | ClientGameMessage::ResetGame | ClientGameMessage::SetSpeed { .. }) if client.spectator => {
+                Some(ServerMessage::error("Spectators can't play"))
+            },
             (Some(gamestate), ClientGameMessage::Input { direction }) => {
-                gamestate.handle_input(direction);
+                if client.count_input(self.config.max_inputs_per_second) {
+                    warn!(client_id = %clientid, rate = client.input_rate, "Inputs too fast for a human, keeping its scores off the leaderboard");
+                }
+                gamestate.handle_input(&clientid, direction);
                 None
             },
+            // on a shared board these affect everyone
+            (Some(gamestate), ClientGameMessage::ResetGame | ClientGameMessage::SetSpeed { .. })
+                if gamestate.owner.as_ref().is_some_and(|owner| owner != &clientid) => {
+                Some(ServerMessage::error("Only the room owner can do that"))
+            },
             (Some(gamestate), ClientGameMessage::ResetGame) => {
-                println!("Resetting game for {}", clientid);
+                debug!(client_id = %clientid, "Resetting game");
                 gamestate.reset();
+                if let Some(id) = &client.game_id {
+                    self.game_started_at.insert(id.clone(), Instant::now());
+                    refreshed_game_id = Some(id.clone());
+                    reset_game_id = Some(id.clone());
+                }
+                None // everyone gets a game_reset, the resetter included
+            },
+            (Some(_), ClientGameMessage::Resync) => {
+                client.needs_full_state = true;
                 None
             },
             (Some(gamestate), ClientGameMessage::SetSpeed { interval }) => {
-                gamestate.interval = interval;
-                None
+                let min_interval = self.config.min_tick_ms.min(u16::MAX as u64) as u16;
+                let max_interval = self.config.max_interval.max(min_interval);
+                gamestate.interval = interval.clamp(min_interval, max_interval);
+                if gamestate.interval != interval {
+                    Some(ServerMessage::error(&format!(
+                        "Interval must be between {} and {} ms, using {}",
+                        min_interval, max_interval, gamestate.interval,
+                    )))
+                } else {
+                    None
+                }
             },
             // User may be sending username after gameover, so we can register it
-            (Some(gamestate), ClientGameMessage::Username { username }) if (gamestate.game_over) && (client.username == None) => {
-                if None == client.username {
+            (Some(gamestate), ClientGameMessage::Username { username }) if gamestate.game_over && client.username.is_none() && !client.spectator && !client.suspect => {
+                if client.username.is_none() {
                     client.username = Some(username.clone());
-                    self.high_scores.push(HighScoreEntry {
-                        username: username,
-                        score: gamestate.score as u32,
-                    });
-                    Some(ServerMessage::HighScores(HighScores::from_vec(&mut self.high_scores)))
+                    let board = gamestate.board_key();
+                    let snake = gamestate.snakes.get(&clientid);
+                    let entry = HighScoreEntry {
+                        username,
+                        score: snake.map(|snake| snake.score).unwrap_or(0).max(0) as u32,
+                        won: (gamestate.won && snake.is_some_and(|snake| snake.alive)) || gamestate.winner.as_ref() == Some(&clientid),
+                    };
+                    self.observer.on_high_score(&entry);
+                    self.high_scores_dirty |= self.high_scores.push(Some(&board), entry);
+                    Some(ServerMessage::HighScores(HighScores::from_board(&self.high_scores, Some(&board))))
                 } else {
                     None
                 }
@@ -346,29 +1314,583 @@ impl GameServer {
                 client.username = Some(username);
                 None
             },
+            (Some(gamestate), ClientGameMessage::LeaveGame) => {
+                gamestate.remove_snake(&clientid);
+                left_game_id = client.game_id.take();
+                Some(ServerMessage::LeftGame)
+            },
             (_, ClientGameMessage::Ping) => Some(ServerMessage::Pong),
+            // everything else needs a game to act on
+            (None, msg) => Some(ServerMessage::error(&format!("Join a game before sending {}", msg.type_name()))),
             (_, _) => None,
         };
+        if let Some(game_id) = left_game_id {
+            self.broadcast_to_game_except(
+                &game_id,
+                &clientid,
+                &ServerMessage::PlayerLeft { client_id: clientid.clone() },
+            ).await;
+            self.hand_over_room(&game_id, &clientid).await;
+            self.drop_game_if_abandoned(&game_id);
+        }
+        if let Some(game_id) = joined_game_id {
+            self.observer.on_join(&clientid, &game_id);
+            let username = self.clients.get(&clientid).and_then(|client| client.username.clone());
+            self.broadcast_to_game_except(
+                &game_id,
+                &clientid,
+                &ServerMessage::PlayerJoined { client_id: clientid.clone(), username },
+            ).await;
+        }
+        if let Some(game_id) = reset_game_id {
+            // right away rather than on the next tick
+            self.broadcast_to_game(&game_id, &ServerMessage::GameReset { by: clientid.clone() }).await;
+            // each in the encoding it asked for, no deltas across a reset
+            let mut frames: HashMap<StateEncoding, Option<Vec<u8>>> = HashMap::new();
+            let recipients: Vec<(String, StateEncoding)> = self.clients
+                .values()
+                .filter(|client| client.websocket && client.game_id.as_deref() == Some(game_id.as_str()))
+                .map(|client| (client.id.clone(), client.state_encoding(false)))
+                .collect();
+            for (client_id, encoding) in recipients {
+                let frame = frames.entry(encoding).or_insert_with(|| {
+                    self.games.get(&game_id).and_then(|game| state_frame(&game_id, game, encoding))
+                });
+                if let Some(frame) = frame {
+                    if let Err(e) = self.send_websocket_frame(&client_id, frame).await {
+                        warn!(%client_id, error = %e, "Failed to send");
+                    }
+                }
+            }
+        }
+        if let Some(game_id) = refreshed_game_id {
+            for client in self.clients.values_mut() {
+                if client.game_id.as_ref() == Some(&game_id) {
+                    client.needs_full_state = true;
+                }
+            }
+        }
         if let Some(res) = client_response {
             let _ = self.send_websocket_response(&clientid, &res).await;
         }
     }
 
-    async fn send_http_response(&mut self, client_id: &str, res: HttpResponse) {
+    // Says goodbye (close frame, if it speaks websocket) and forgets the client
+    async fn disconnect_client(&mut self, client_id: &str) {
+        let Some(mut client) = self.clients.remove(client_id) else {
+            return;
+        };
+        info!(%client_id, game_id = client.game_id.as_deref(), "Client disconnected");
+        self.observer.on_disconnect(client_id);
+        // a player that may come back keeps its snake
+        let snake_id = client.game_id.as_ref()
+            .filter(|_| client.websocket && !client.spectator)
+            .and_then(|game_id| self.games.get(game_id))
+            .filter(|game| game.snakes.contains_key(client_id))
+            .map(|_| client_id.to_string());
+        if client.websocket {
+            // in case it comes back with its session token
+            self.sessions.insert(client.session_token.clone(), DroppedSession {
+                game_id: client.game_id.clone(),
+                snake_id: snake_id.clone(),
+                username: client.username.clone(),
+                delta_updates: client.delta_updates,
+                binary_updates: client.binary_updates,
+                summary_updates: client.summary_updates,
+                suspect: client.suspect,
+                dropped_at: Instant::now(),
+            });
+        }
+        if client.websocket {
+            // answers a close frame, or is just ignored if the socket is gone
+            client.enqueue(WebSocketFrame::control(WebSocketFrame::OPCODE_CLOSE, Vec::new()));
+        }
+        // dropping the queue is what gets the writer to close the socket
+        drop(client.outbound.take());
+        if let Some(game_id) = client.game_id {
+            // nobody is steering that snake anymore, and won't be
+            if snake_id.is_none() {
+                if let Some(game) = self.games.get_mut(&game_id) {
+                    game.remove_snake(client_id);
+                }
+            }
+            self.broadcast_to_game_except(
+                &game_id,
+                client_id,
+                &ServerMessage::PlayerLeft { client_id: client_id.to_string() },
+            ).await;
+            self.hand_over_room(&game_id, client_id).await;
+            self.drop_game_if_abandoned(&game_id);
+        }
+    }
+
+    // When the owner of game_id leaves, the player that has been around the
+    // longest takes over. With no players left the room goes ownerless until
+    // someone joins it (a dropped owner resuming its session, say), the usual
+    // cleanup takes it away otherwise
+    async fn hand_over_room(&mut self, game_id: &str, leaving_client_id: &str) {
+        let Some(game) = self.games.get_mut(game_id) else {
+            return;
+        };
+        if game.owner.as_deref() != Some(leaving_client_id) {
+            return;
+        }
+        let new_owner = self.clients
+            .values()
+            .filter(|client| client.game_id.as_deref() == Some(game_id) && !client.spectator)
+            .map(|client| client.id.clone())
+            .min_by_key(|id| (id.len(), id.clone())); // ids are counters, lowest is oldest
+        game.owner = new_owner.clone();
+        if let Some(new_owner) = new_owner {
+            self.broadcast_to_game(game_id, &ServerMessage::OwnerChanged { client_id: new_owner }).await;
+        }
+    }
+
+    // Hands the dropped session's username and token over to `clientid`,
+    // returning the game it was in if that still exists
+    fn resume_session(&mut self, clientid: &str, token: String) -> Result<Option<String>, &'static str> {
+        let session = self.sessions.remove(&token).ok_or("Unknown or expired session")?;
+        let client = self.clients.get_mut(clientid).unwrap();
+        if client.username.is_none() {
+            client.username = session.username;
+        }
+        client.delta_updates = session.delta_updates;
+        client.binary_updates = session.binary_updates;
+        client.summary_updates = session.summary_updates;
+        client.suspect |= session.suspect;
+        client.session_token = token;
+        if let (Some(game_id), Some(snake_id)) = (&session.game_id, &session.snake_id) {
+            if let Some(game) = self.games.get_mut(game_id) {
+                game.rename_snake(snake_id, clientid);
+            }
+        }
+        Ok(session.game_id.filter(|game_id| self.games.contains_key(game_id)))
+    }
+
+    async fn handle_chat(&mut self, clientid: &str, text: String) {
+        let client = self.clients.get(clientid).unwrap();
+        let text = text.trim().to_string();
+        let error = if client.game_id.is_none() {
+            Some("Join a game to chat")
+        } else if text.is_empty() {
+            Some("Empty chat message")
+        } else if text.chars().count() > self.config.max_chat_len {
+            Some("Chat message is too long")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            let _ = self.send_websocket_response(clientid, &ServerMessage::error(error)).await;
+            return;
+        }
+        let game_id = client.game_id.clone().unwrap();
+        let message = ServerMessage::Chat {
+            username: client.username.clone().unwrap_or_else(|| client.id.clone()),
+            text,
+        };
+        self.broadcast_to_game(&game_id, &message).await;
+    }
+
+    // Everyone in `game_id`
+    async fn broadcast_to_game(&mut self, game_id: &str, message: &ServerMessage) {
+        self.broadcast(game_id, None, message).await;
+    }
+
+    // Everyone in `game_id` but `except_client_id`
+    async fn broadcast_to_game_except(&mut self, game_id: &str, except_client_id: &str, message: &ServerMessage) {
+        self.broadcast(game_id, Some(except_client_id), message).await;
+    }
+
+    // The message is serialized once, every client gets the very same frame
+    async fn broadcast(&mut self, game_id: &str, except_client_id: Option<&str>, message: &ServerMessage) {
+        let frame = match serde_json::to_string(message) {
+            Ok(json) => WebSocketFrame::to_websocket(json.into_bytes()),
+            Err(e) => {
+                error!(%game_id, error = %e, "Failed to serialize broadcast");
+                return;
+            },
+        };
+        self.broadcast_frame(game_id, except_client_id, &frame).await;
+    }
+
+    async fn broadcast_frame(&mut self, game_id: &str, except_client_id: Option<&str>, frame: &[u8]) {
+        let client_ids: Vec<String> = self.clients
+            .values()
+            .filter(|client| client.game_id.as_deref() == Some(game_id))
+            .filter(|client| Some(client.id.as_str()) != except_client_id)
+            .map(|client| client.id.clone())
+            .collect();
+        for client_id in client_ids {
+            if let Err(e) = self.send_websocket_frame(&client_id, frame).await {
+                warn!(%client_id, error = %e, "Failed to send");
+            }
+        }
+    }
+
+    // Whether there's no place left in game_id for client_id, who may be
+    // in there already (e.g. switching from spectating to playing)
+    fn is_game_full(&self, game_id: &str, client_id: &str) -> bool {
+        let Some(max_players) = self.games.get(game_id).and_then(|game| game.max_players) else {
+            return false;
+        };
+        let in_room = self.clients
+            .values()
+            .filter(|client| client.id != client_id && client.game_id.as_deref() == Some(game_id))
+            .count();
+        in_room >= max_players
+    }
+
+    // The trimmed username, if it is fit for the high scores and the chat
+    fn validate_username(&self, username: &str) -> Result<String, String> {
+        let username = username.trim();
+        if username.is_empty() {
+            Err("Empty username".to_string())
+        } else if username.chars().count() > self.config.max_username_len {
+            Err(format!("Username is too long, {} characters at most", self.config.max_username_len))
+        } else if username.chars().any(char::is_control) {
+            Err("Username has control characters".to_string())
+        } else {
+            Ok(username.to_string())
+        }
+    }
+
+    // Someone else in client_id's game already goes by that name (ignoring
+    // case and surrounding spaces)
+    fn is_username_taken(&self, client_id: &str, username: &str) -> bool {
+        let Some(game_id) = self.clients.get(client_id).and_then(|client| client.game_id.as_deref()) else {
+            return false;
+        };
+        let username = username.trim().to_lowercase();
+        self.clients.values().any(|client| {
+            client.id != client_id
+                && client.game_id.as_deref() == Some(game_id)
+                && client.username.as_ref().is_some_and(|other| other.trim().to_lowercase() == username)
+        })
+    }
+
+    fn room_summaries(&self) -> Vec<RoomSummary> {
+        let mut summaries: Vec<RoomSummary> = self.games
+            .iter()
+            .map(|(game_id, game)| RoomSummary {
+                game_id: game_id.clone(),
+                players: self.clients
+                    .values()
+                    .filter(|client| client.game_id.as_ref() == Some(game_id) && !client.spectator)
+                    .count(),
+                max_players: game.max_players,
+                size: Size { width: game.width, height: game.height },
+                game_over: game.game_over,
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.game_id.cmp(&b.game_id));
+        summaries
+    }
+
+    // Once per tick: writes them if they changed, off the game loop and
+    // never two writes at once
+    fn save_high_scores(&mut self) {
+        let writing = self.high_scores_write.as_ref().is_some_and(|write| !write.is_finished());
+        if self.high_scores_dirty && !writing && self.high_scores_saved_at.elapsed() >= HIGH_SCORES_SAVE_INTERVAL {
+            self.high_scores_write = Some(self.write_high_scores());
+        }
+    }
+
+    fn write_high_scores(&mut self) -> JoinHandle<()> {
+        self.high_scores_dirty = false;
+        self.high_scores_saved_at = Instant::now();
+        let path = self.config.highscore_file.clone();
+        let json = serde_json::to_string(&self.high_scores);
+        tokio::task::spawn_blocking(move || {
+            let result = json
+                .map_err(Error::from)
+                .and_then(|json| std::fs::write(&path, json));
+            if let Err(err) = result {
+                warn!(path = %path.display(), error = %err, "Couldn't save high scores");
+            }
+        })
+    }
+
+    fn is_game_in_use(&self, game_id: &str) -> bool {
+        self.clients
+            .values()
+            .any(|client| client.game_id.as_deref() == Some(game_id))
+            || self.sessions
+                .values()
+                .any(|session| session.snake_id.is_some() && session.game_id.as_deref() == Some(game_id))
+    }
+
+    // Games nobody is in anymore would otherwise linger forever
+    fn drop_game_if_abandoned(&mut self, game_id: &str) {
+        if !self.is_game_in_use(game_id) {
+            self.remove_game(game_id);
+        }
+    }
+
+    fn remove_game(&mut self, game_id: &str) {
+        self.games.remove(game_id);
+        self.interval_buffer.remove(game_id);
+        self.game_started_at.remove(game_id);
+        self.game_finished_at.remove(game_id);
+    }
+
+    async fn send_http_response(&mut self, client_id: &str, mut res: HttpResponse) {
         let client = self.clients.get_mut(client_id).unwrap();
-        let _ = client.stream.write_all( &res.as_bytes() ).await;
+        client.enqueue(res.as_bytes());
+        if let Some(chunks) = res.take_chunks() {
+            client.enqueue_outbound(Outbound::Chunks(chunks));
+        }
     }
 
     async fn send_websocket_response(&mut self, client_id: &str, message: &ServerMessage) -> Result<(), Box<dyn std::error::Error>> {
         let json = serde_json::to_string(message)?;
-        let frame = WebSocketFrame::to_websocket(json.as_bytes().to_vec());
+        let frame = WebSocketFrame::to_websocket(json.into_bytes());
+        self.send_websocket_frame(client_id, &frame).await
+    }
+
+    // For frames already built, e.g. shared by several clients
+    async fn send_websocket_frame(&mut self, client_id: &str, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
         let client = self.clients.get_mut(client_id).unwrap();
-        let _ = &mut client.stream.write_all(&frame).await;
+        if client.send_failed {
+            return Err("already falling behind".into());
+        }
+        if !client.enqueue(frame.to_vec()) {
+            return Err("outbound queue is full".into());
+        }
         Ok(())
     }
     async fn send_websocket_highscores(&mut self, client_id: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let highscores = ServerMessage::HighScores(HighScores::from_vec(&mut self.high_scores)); 
-        println!("Sending highscores to {}", client_id);
+        let highscores = ServerMessage::HighScores(HighScores::from_board(&self.high_scores, None));
+        debug!(%client_id, "Sending highscores");
         self.send_websocket_response(client_id, &highscores).await
     }
 }
+
+// Random, hard to guess and formatted like an uuid
+fn new_session_token() -> String {
+    let hex = format!("{:032x}", rand::random::<u128>());
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// Where `filepath` (as asked in the url) lives under `root`, or None if it
+// doesn't exist or points outside of it
+fn resolve_static_path(root: &Path, filepath: &str) -> Option<PathBuf> {
+    if filepath.split(['/', '\\']).any(|segment| segment == "..") {
+        return None;
+    }
+    let root = root.canonicalize().ok()?;
+    let resolved = root.join(filepath).canonicalize().ok()?;
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+// A game as `encoding` puts it, ready to go out as a websocket frame
+fn state_frame(game_id: &str, gamestate: &GameState, encoding: StateEncoding) -> Option<Vec<u8>> {
+    let frame = match (encoding, gamestate.last_delta()) {
+        (StateEncoding::Binary, _) => Ok(WebSocketFrame::to_binary_frame(gamestate.to_binary())),
+        (StateEncoding::Summary, _) => serde_json::to_vec(&ServerMessage::GameSummary(gamestate.summary()))
+            .map(WebSocketFrame::to_websocket),
+        (StateEncoding::Delta, Some(delta)) => ServerMessage::game_delta_json(delta)
+            .map(|json| WebSocketFrame::to_websocket(json.into_bytes())),
+        _ => ServerMessage::game_state_json(gamestate)
+            .map(|json| WebSocketFrame::to_websocket(json.into_bytes())),
+    };
+    frame.map_err(|e| error!(%game_id, error = %e, "Failed to serialize game")).ok()
+}
+
+// Whether a game still running has gone past max_game_duration by `now`
+fn has_expired(game: &GameState, started_at: Option<Instant>, max_duration: Option<Duration>, now: Instant) -> bool {
+    !game.game_over && match (max_duration, started_at) {
+        (Some(max_duration), Some(started_at)) => now.saturating_duration_since(started_at) >= max_duration,
+        _ => false,
+    }
+}
+
+// GET/HEAD of whatever is under `static_dir`
+fn static_file_response(static_dir: &Path, index_file: &str, req: &HttpRequest, gzip_cache: &GzipCache) -> HttpResponse {
+    let filepath = match req.path.strip_prefix('/') {
+        Some("") => index_file,
+        Some(filepath) => filepath,
+        None => return HttpResponse::bad_request(), // "GET x HTTP/1.1", no idea where that is
+    };
+    let resolved = resolve_static_path(static_dir, filepath);
+    let validators = resolved.as_ref().and_then(|filepath| HttpResponse::file_validators(&filepath.to_string_lossy()));
+    match (resolved, validators) {
+        (Some(_), Some((etag, last_modified))) if req.is_fresh(&etag, &last_modified) => {
+            HttpResponse::not_modified(&etag, &last_modified)
+        },
+        (Some(filepath), _) if req.method == HttpMethod::HEAD => {
+            HttpResponse::file_headers(&filepath.to_string_lossy())
+        },
+        (Some(filepath), _) if req.accepts_gzip() => {
+            HttpResponse::file_content_gzipped(&filepath.to_string_lossy(), gzip_cache)
+        },
+        (Some(filepath), _) => HttpResponse::file_content(&filepath.to_string_lossy()),
+        (None, _) => {
+            debug!(path = %req.path, "Asked for a file which is not ours to give");
+            HttpResponse::not_found()
+        },
+    }
+}
+
+// Reads and parses whatever a client sends, passing it on to the game loop
+// until the connection is gone
+async fn read_client(client_id: String, mut tcp_rx: impl AsyncRead + Unpin, events: GameEventSender) {
+    let mut buff = [0; 2048];
+    let mut vec_buff = Vec::new();
+    let mut fragments = Vec::new();
+    loop {
+        match tcp_rx.read(&mut buff).await {
+            Err(err) => {
+                let _ = events.client_input(
+                    &client_id,
+                    ClientMessage::Invalid(InvalidReason::ReadError(err.to_string())),
+                );
+                // nothing else will come out of this socket
+                let _ = events.client_input(&client_id, ClientMessage::Disconnect);
+                break;
+            },
+            Ok(0) => {
+                let _ = events.client_input(&client_id, ClientMessage::Disconnect);
+                break;
+            },
+            Ok(n) => {
+                vec_buff.extend_from_slice(&buff[0..n]);
+                if vec_buff.len() > MAX_HTTP_BUFFER_LEN {
+                    warn!(%client_id, limit = MAX_HTTP_BUFFER_LEN, "Client buffer is huge, clearing it");
+                    vec_buff.clear();
+                }
+                // a single read may carry several messages,
+                // keep going while bytes are being consumed
+                loop {
+                    let buff_len = vec_buff.len();
+                    let parsed_input = parse_client_message(&mut vec_buff, &mut fragments);
+                    if !matches!(parsed_input, ClientMessage::Incomplete) {
+                        let _ = events.client_input(&client_id, parsed_input);
+                    }
+                    if vec_buff.is_empty() || vec_buff.len() == buff_len {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+}
+
+// A missing or broken file just means starting over with no high scores
+fn load_high_scores(path: &Path) -> HighScoreBoards {
+    // files from before there were per board high scores are a plain list
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum HighScoresFile {
+        Boards(HighScoreBoards),
+        GlobalOnly(Vec<HighScoreEntry>),
+    }
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(HighScoresFile::Boards(boards)) => boards.trimmed(),
+            Ok(HighScoresFile::GlobalOnly(global)) => HighScoreBoards { global, ..Default::default() }.trimmed(),
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "Ignoring corrupt high scores file");
+                HighScoreBoards::default()
+            },
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => HighScoreBoards::default(),
+        Err(err) => {
+            warn!(path = %path.display(), error = %err, "Couldn't read high scores file");
+            HighScoreBoards::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn games_expire_at_the_max_duration() {
+        let mut game = GameState::new_seeded(20, 20, 7);
+        game.add_snake("a");
+        let started_at = Instant::now();
+        let max_duration = Some(Duration::from_secs(600));
+
+        assert!(!has_expired(&game, Some(started_at), max_duration, started_at + Duration::from_secs(599)));
+        assert!(has_expired(&game, Some(started_at), max_duration, started_at + Duration::from_secs(600)));
+        // no limit, no expiry
+        assert!(!has_expired(&game, Some(started_at), None, started_at + Duration::from_secs(86400)));
+        // already over, nothing left to end
+        game.game_over = true;
+        assert!(!has_expired(&game, Some(started_at), max_duration, started_at + Duration::from_secs(600)));
+    }
+
+    #[tokio::test]
+    async fn pongs_count_as_activity() {
+        let mut server = GameServer::new();
+        let (stream, _client_end) = tokio::io::duplex(1024);
+        server.handle_io_event(GameEvent::NewConnection(ClientConnection::new("1", stream))).await;
+        let connected_at = server.clients["1"].last_seen;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        server.handle_io_event(GameEvent::ClientInput("1".to_string(), ClientMessage::Pong)).await;
+        assert!(server.clients["1"].last_seen > connected_at);
+    }
+
+    #[tokio::test]
+    async fn resumed_players_get_their_snake_back() {
+        let mut server = GameServer::new();
+        let (stream, _client_end) = tokio::io::duplex(1 << 16);
+        server.handle_io_event(GameEvent::NewConnection(ClientConnection::new("1", stream))).await;
+        server.clients.get_mut("1").unwrap().websocket = true;
+        let join = ClientGameMessage::JoinGame(JoinGame::default());
+        server.handle_io_event(GameEvent::ClientInput("1".to_string(), ClientMessage::ClientGameMessage(join))).await;
+        let game_id = server.clients["1"].game_id.clone().unwrap();
+        let token = server.clients["1"].session_token.clone();
+
+        // the only player drops: the game waits for it
+        server.handle_io_event(GameEvent::ClientInput("1".to_string(), ClientMessage::Disconnect)).await;
+        let game = &server.games[&game_id];
+        assert!(!game.game_over);
+        let head = game.snakes["1"].head();
+        server.handle_io_event(GameEvent::GameTick).await;
+        assert_eq!(server.games[&game_id].snakes["1"].head(), head);
+
+        let (stream, _client_end) = tokio::io::duplex(1 << 16);
+        server.handle_io_event(GameEvent::NewConnection(ClientConnection::new("2", stream))).await;
+        server.clients.get_mut("2").unwrap().websocket = true;
+        let resume = ClientGameMessage::Resume { token };
+        server.handle_io_event(GameEvent::ClientInput("2".to_string(), ClientMessage::ClientGameMessage(resume))).await;
+        assert_eq!(server.clients["2"].game_id.as_ref(), Some(&game_id));
+
+        server.handle_io_event(GameEvent::GameTick).await;
+        let game = &server.games[&game_id];
+        assert!(!game.snakes.contains_key("1"));
+        assert!(game.snakes["2"].alive);
+        assert_ne!(game.snakes["2"].head(), head);
+        assert!(!game.game_over);
+    }
+
+    #[test]
+    fn head_on_dynamic_routes_is_get_without_the_body() {
+        let mut server = GameServer::new();
+        let request = |method: HttpMethod| HttpRequest {
+            method,
+            version: "HTTP/1.1".to_string(),
+            path: "/healthz".to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: None,
+        };
+        let get = String::from_utf8(server.route_http_request("1", &request(HttpMethod::GET)).unwrap().as_bytes()).unwrap();
+        let head = String::from_utf8(server.route_http_request("1", &request(HttpMethod::HEAD)).unwrap().as_bytes()).unwrap();
+        assert!(get.starts_with("HTTP/1.1 200"));
+        assert!(get.ends_with("\r\n\r\nok"));
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.ends_with("\r\n\r\n"));
+        // content-length of what GET would send
+        assert!(head.contains("content-length: 2\r\n"));
+    }
+
+    #[test]
+    fn zero_tick_is_clamped() {
+        let server = GameServer::with_config(GameServerConfig { min_tick_ms: 0, ..Default::default() });
+        assert_eq!(server.config.min_tick_ms, 1);
+    }
+}
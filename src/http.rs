@@ -1,12 +1,22 @@
-use std::{collections::HashMap, fs, io::{Error, Write}, str::FromStr};
+use std::{collections::HashMap, fs, io::{Error, Read, Write}, str::FromStr, sync::Mutex, time::UNIX_EPOCH};
 
 use base64::Engine;
+use flate2::{write::GzEncoder, Compression};
 use sha1::Digest;
+use tracing::error;
 
+// Smaller bodies aren't worth gzipping
+static GZIP_MIN_LEN: usize = 1024;
+// Files this big are streamed in chunks instead of read whole into memory
+static CHUNKED_MIN_LEN: u64 = 1024 * 1024;
+static CHUNK_LEN: usize = 64 * 1024;
+// Everything the router answers to, for both Allow and the CORS preflight
+static ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
@@ -18,6 +28,8 @@ impl FromStr for HttpMethod {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.starts_with("GET") {
             Ok(HttpMethod::GET)
+        } else if s.starts_with("HEAD") {
+            Ok(HttpMethod::HEAD)
         } else if s.starts_with("POST") {
             Ok(HttpMethod::POST)
         } else if s.starts_with("PUT") {
@@ -27,25 +39,66 @@ impl FromStr for HttpMethod {
         } else if s.starts_with("OPTIONS") {
             Ok(HttpMethod::OPTIONS)
         } else {
-            return Err("wtf this is not a http method");
+            Err("wtf this is not a http method")
         }
     }
     
     type Err = &'static str;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub version: String,
-    pub path: String,
-    pub headers: HashMap<String, String>,
+    pub path: String, // without the query string
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>, // names lowercased, look them up that way
+    pub body: Option<Vec<u8>>, // only when there was a Content-Length
 }
 
 impl HttpRequest {
+    // Whether the socket should stay open for more requests once this one is
+    // answered. That's the default since HTTP/1.1
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get("connection") {
+            Some(connection) if connection.trim().eq_ignore_ascii_case("close") => false,
+            Some(connection) if connection.trim().eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+    pub fn accepts_gzip(&self) -> bool {
+        match self.headers.get("accept-encoding") {
+            Some(encodings) => encodings
+                .split(',')
+                .any(|encoding| encoding.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("gzip")),
+            None => false
+        }
+    }
+    // "a=1&b=2" -> {a: 1, b: 2}. A key with no '=' gets an empty value
+    pub fn parse_query(query: &str) -> HashMap<String, String> {
+        query.split('&')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (k, v) = part.split_once('=').unwrap_or((part, ""));
+                (k.to_string(), v.to_string())
+            })
+            .collect()
+    }
+
+    // Whether the client's cached copy, going by the validators it sent back,
+    // is still the one we have. If-None-Match wins over If-Modified-Since.
+    pub fn is_fresh(&self, etag: &str, last_modified: &str) -> bool {
+        if let Some(tags) = self.headers.get("if-none-match") {
+            let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            return tags.split(',').any(|tag| tag.trim() == "*" || strip_weak(tag) == strip_weak(etag));
+        }
+        // we only ever hand out our own dates, so they come back just the same
+        self.headers.get("if-modified-since").is_some_and(|since| since.trim() == last_modified)
+    }
+
     pub fn is_websocket_handshake(&self) -> bool {
-        match self.headers.get("Upgrade") {
-            Some(s) => s.eq("websocket"),
+        match self.headers.get("upgrade") {
+            Some(s) => s.trim().eq_ignore_ascii_case("websocket"),
             None => false
         }
     }
@@ -57,6 +110,7 @@ pub struct HttpResponse {
     status_msg: String,
     headers: HashMap<String, String>,
     body: Option<Vec<u8>>,
+    chunks: Option<Box<dyn Iterator<Item = Vec<u8>> + Send>>, // Transfer-Encoding: chunked, instead of body
 }
 
 impl HttpResponse {
@@ -66,17 +120,17 @@ impl HttpResponse {
         ret.insert("Cross-Origin-Opener-Policy".to_string(), "same-origin".to_string());
         ret.insert("Cross-Origin-Embedder-Policy".to_string(), "require-corp".to_string());
         
-        return ret;
+        ret
     }
     fn with_content_length(mut self, size: usize) -> Self {
         self.headers.remove("content-length");
         self.headers.insert("content-length".to_string(), size.to_string());
-        return self;
+        self
     }
     fn with_content_type(mut self, content_type: &str) -> Self {
         self.headers.remove("content-type");
         self.headers.insert("content-type".to_string(), content_type.to_string());
-        return self;
+        self
     }
     pub fn not_found() -> HttpResponse {
         HttpResponse {
@@ -85,56 +139,275 @@ impl HttpResponse {
             status_msg: "No shit".to_string(),
             headers: Self::default_headers(),
             body: None,
+            chunks: None,
         }.with_content_length(0)
     }
+    // Compresses text-ish bodies big enough to be worth it, anything else
+    // goes out untouched
+    pub fn with_gzip(self) -> Self {
+        let compressible = matches!(
+            self.headers.get("content-type").map(String::as_str),
+            Some("text/html" | "text/css" | "text/javascript" | "text/plain" | "application/json" | "application/wasm")
+        );
+        let body = match &self.body {
+            Some(body) if compressible && body.len() >= GZIP_MIN_LEN => body,
+            _ => return self,
+        };
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        match encoder.write_all(body).and_then(|_| encoder.finish()) {
+            Ok(compressed) => self.with_gzipped_body(compressed),
+            Err(err) => {
+                error!(error = %err, "Error gzipping response");
+                self
+            },
+        }
+    }
+    fn with_gzipped_body(mut self, compressed: Vec<u8>) -> Self {
+        let len = compressed.len();
+        self.body = Some(compressed);
+        self.headers.insert("content-encoding".to_string(), "gzip".to_string());
+        self.headers.insert("vary".to_string(), "accept-encoding".to_string());
+        // not the same bytes anymore
+        if let Some(etag) = self.headers.remove("etag") {
+            self.headers.insert("etag".to_string(), format!("W/{}", etag.trim_start_matches("W/")));
+        }
+        self.with_content_length(len)
+    }
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.headers.insert(
+            "connection".to_string(),
+            if keep_alive { "keep-alive" } else { "close" }.to_string(),
+        );
+        self
+    }
+    pub fn json(body: Vec<u8>) -> HttpResponse {
+        let len = body.len();
+        HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 200,
+            status_msg: "Take this".to_string(),
+            headers: Self::default_headers(),
+            body: Some(body),
+            chunks: None,
+        }
+        .with_content_length(len)
+        .with_content_type("application/json")
+    }
+    pub fn text(body: String) -> HttpResponse {
+        Self::json(body.into_bytes()).with_content_type("text/plain")
+    }
+    // Answer to a CORS preflight, the actual request comes next
+    pub fn preflight(req: &HttpRequest) -> HttpResponse {
+        let mut ret = HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 204,
+            status_msg: "Go ahead".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }.with_content_length(0);
+        ret.headers.insert("access-control-allow-methods".to_string(), ALLOWED_METHODS.to_string());
+        if let Some(headers) = req.headers.get("access-control-request-headers") {
+            ret.headers.insert("access-control-allow-headers".to_string(), headers.clone());
+        }
+        ret.headers.insert("access-control-max-age".to_string(), "86400".to_string());
+        ret
+    }
+    // Lets a page from `origin` read this response
+    pub fn with_cors(mut self, origin: &str) -> Self {
+        self.headers.insert("access-control-allow-origin".to_string(), origin.to_string());
+        let vary = match self.headers.remove("vary") {
+            Some(vary) => format!("{}, origin", vary),
+            None => "origin".to_string(),
+        };
+        self.headers.insert("vary".to_string(), vary);
+        self
+    }
+    pub fn bad_request() -> HttpResponse {
+        HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 400,
+            status_msg: "What is this".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }.with_content_length(0)
+    }
+    pub fn method_not_allowed() -> HttpResponse {
+        let mut ret = HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 405,
+            status_msg: "Not like that".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }.with_content_length(0);
+        ret.headers.insert("allow".to_string(), ALLOWED_METHODS.to_string());
+        ret
+    }
+    pub fn not_modified(etag: &str, last_modified: &str) -> HttpResponse {
+        let mut ret = HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 304,
+            status_msg: "You already have it".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        };
+        ret.headers.insert("etag".to_string(), etag.to_string());
+        ret.headers.insert("last-modified".to_string(), last_modified.to_string());
+        ret
+    }
+    pub fn service_unavailable() -> HttpResponse {
+        HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 503,
+            status_msg: "Too many snakes".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }.with_content_length(0).with_keep_alive(false)
+    }
+    pub fn upgrade_required() -> HttpResponse {
+        let mut ret = HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 426,
+            status_msg: "Upgrade Required".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }.with_content_length(0);
+        // the only websocket version we speak
+        ret.headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+        ret
+    }
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+    // What a HEAD request gets of it: same headers, content-length included
+    pub fn without_body(mut self) -> Self {
+        self.body = None;
+        self.chunks = None;
+        self
+    }
     pub fn file_content(filepath: &str) -> HttpResponse {
+        if fs::metadata(filepath).is_ok_and(|metadata| metadata.len() >= CHUNKED_MIN_LEN) {
+            match fs::File::open(filepath) {
+                Ok(mut file) => {
+                    let chunks = std::iter::from_fn(move || {
+                        let mut chunk = vec![0; CHUNK_LEN];
+                        match file.read(&mut chunk) {
+                            Ok(0) => None,
+                            Ok(n) => {
+                                chunk.truncate(n);
+                                Some(chunk)
+                            },
+                            Err(err) => {
+                                // too late for an error response, the client gets a cut short body
+                                error!(error = %err, "Error streaming file");
+                                None
+                            },
+                        }
+                    });
+                    return Self::file_headers_only(filepath, 0).with_chunked_body(chunks);
+                },
+                Err(err) => {
+                    error!(%filepath, error = %err, "Error reading file");
+                    return Self::not_found();
+                },
+            }
+        }
         match fs::read(filepath) {
             Err(err) => {
-                println!("error reading file '{}': {}", filepath, err);
+                error!(%filepath, error = %err, "Error reading file");
                 Self::not_found()
             },
             Ok(payload) => {
                 let len = payload.len();
-                let file_extension = filepath.split('.').last();
-                HttpResponse {
-                    protocol_version: "HTTP/1.1".to_string(),
-                    status_code: 200,
-                    status_msg: "Take this".to_string(),
-                    headers: Self::default_headers(),
-                    body: Some(payload),
-                }
-                .with_content_length(len)
-                .with_content_type(
-                    match file_extension {
-                        Some(ext) if ext.to_ascii_lowercase().eq("js") => {
-                            "text/javascript"
-                        },
-                        Some(ext) if ext.to_ascii_lowercase().eq("html") => {
-                            "text/html"
-                        },
-                        Some(ext) if ext.to_ascii_lowercase().eq("css") => {
-                            "text/css"
-                        },
-                        _ => "application/octet-stream"
-                    }
-                )
+                let mut ret = Self::file_headers_only(filepath, len);
+                ret.body = Some(payload);
+                ret
+            }
+        }
+    }
+
+    // Same as file_content().with_gzip(), but each version of a file (going
+    // by its etag) is only compressed once
+    pub fn file_content_gzipped(filepath: &str, cache: &GzipCache) -> HttpResponse {
+        let etag = Self::file_validators(filepath).map(|(etag, _)| etag);
+        if let Some(etag) = &etag {
+            if let Some(compressed) = cache.get(filepath, etag) {
+                return Self::file_headers_only(filepath, 0).with_gzipped_body(compressed);
             }
         }
+        let response = Self::file_content(filepath).with_gzip();
+        if let (Some(etag), Some(body)) = (etag, &response.body) {
+            if response.headers.get("content-encoding").is_some_and(|encoding| encoding == "gzip") {
+                cache.insert(filepath, etag, body.clone());
+            }
+        }
+        response
     }
 
-    pub fn websocket_handshake(req:&HttpRequest) -> HttpResponse {
-        let key = req.headers.get("Sec-WebSocket-Key").unwrap();
+    // What a HEAD request gets: same headers as file_content, but no body
+    pub fn file_headers(filepath: &str) -> HttpResponse {
+        match fs::metadata(filepath) {
+            Ok(metadata) if metadata.is_file() => {
+                Self::file_headers_only(filepath, metadata.len() as usize)
+            },
+            Ok(_) => Self::not_found(),
+            Err(err) => {
+                error!(%filepath, error = %err, "Error reading file");
+                Self::not_found()
+            },
+        }
+    }
+
+    // ETag and Last-Modified for a file, from its length and mtime
+    pub fn file_validators(filepath: &str) -> Option<(String, String)> {
+        let metadata = fs::metadata(filepath).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some((format!("\"{:x}-{:x}\"", metadata.len(), modified), http_date(modified)))
+    }
+
+    fn file_headers_only(filepath: &str, len: usize) -> HttpResponse {
+        let file_extension = filepath.split('.').next_back();
+        let mut ret = HttpResponse {
+            protocol_version: "HTTP/1.1".to_string(),
+            status_code: 200,
+            status_msg: "Take this".to_string(),
+            headers: Self::default_headers(),
+            body: None,
+            chunks: None,
+        }
+        .with_content_length(len)
+        .with_content_type(content_type_for(file_extension.unwrap_or_default()));
+        if let Some((etag, last_modified)) = Self::file_validators(filepath) {
+            ret.headers.insert("etag".to_string(), etag);
+            ret.headers.insert("last-modified".to_string(), last_modified);
+        }
+        ret
+    }
+
+    // `subprotocols` are the ones we speak, the first one the client asked
+    // for (in its order of preference) is echoed back
+    pub fn websocket_handshake(req:&HttpRequest, subprotocols: &[String]) -> HttpResponse {
+        if req.headers.get("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+            return Self::upgrade_required();
+        }
+        let Some(key) = req.headers.get("sec-websocket-key") else {
+            return Self::bad_request();
+        };
 
         let mut hasher = sha1::Sha1::new();
         let fullstring = format!("{}{}", key, "258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
         let accept_key = match hasher.write(fullstring.as_bytes()) {
             Ok(_result) => {
                 let finished = hasher.finalize();
-                let ret = base64::engine::general_purpose::STANDARD.encode(finished);
-                ret
+                base64::engine::general_purpose::STANDARD.encode(finished)
             },
             Err(err) => {
-                println!("Error writing hash: {}", err);
+                error!(error = %err, "Error writing hash");
                 "".to_string()
             }
         };
@@ -143,17 +416,50 @@ impl HttpResponse {
         headers.insert("Sec-Websocket-Accept".to_string(), accept_key);
         headers.insert("Connection".to_string(), "upgrade".to_string());
         headers.insert("Upgrade".to_string(), "websocket".to_string());
-
+        let chosen_protocol = req.headers
+            .get("sec-websocket-protocol")
+            .and_then(|requested| requested.split(',').map(str::trim).find(|p| subprotocols.iter().any(|ours| ours == p)));
+        if let Some(protocol) = chosen_protocol {
+            headers.insert("Sec-WebSocket-Protocol".to_string(), protocol.to_string());
+        }
 
         HttpResponse {
           protocol_version: "HTTP/1.1".to_string(),
           status_code: 101,
           status_msg: "Lets gooo".to_string(),
-          headers: headers,
-          body: None, 
+          headers,
+          body: None,
+          chunks: None,
         }
     }
 
+    // The body goes out piece by piece as the iterator yields them, so it
+    // never has to be in memory all at once. Replaces any body set before.
+    pub fn with_chunked_body(mut self, chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Self {
+        self.headers.remove("content-length");
+        self.headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        self.body = None;
+        self.chunks = Some(Box::new(chunks));
+        self
+    }
+
+    // What to send after as_bytes(), if the body is chunked: each one goes
+    // through chunk_frame, then LAST_CHUNK
+    pub fn take_chunks(&mut self) -> Option<Box<dyn Iterator<Item = Vec<u8>> + Send>> {
+        self.chunks.take()
+    }
+
+    pub const LAST_CHUNK: &'static [u8] = b"0\r\n\r\n";
+
+    // Size in hex, CRLF, data, CRLF. An empty chunk comes out as LAST_CHUNK
+    pub fn chunk_frame(chunk: &[u8]) -> Vec<u8> {
+        let mut frame = format!("{:x}\r\n", chunk.len()).into_bytes();
+        frame.extend_from_slice(chunk);
+        frame.extend_from_slice(b"\r\n");
+        frame
+    }
+
+    // Status line and headers, plus the body unless it is chunked
     pub fn as_bytes(&self) -> Vec<u8> {
         let status_line = format!(
             "{} {} {}", 
@@ -180,14 +486,109 @@ impl HttpResponse {
 
 }
 
+// Gzipped static files, so they aren't compressed again for every request.
+// Shared by whoever serves them, from any thread
+#[derive(Default)]
+pub struct GzipCache {
+    entries: Mutex<HashMap<String, (String, Vec<u8>)>>, // filepath -> (etag it was compressed from, gzipped body)
+}
+
+impl GzipCache {
+    fn get(&self, filepath: &str, etag: &str) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.get(filepath).filter(|(cached_etag, _)| cached_etag == etag).map(|(_, body)| body.clone())
+    }
+
+    fn insert(&self, filepath: &str, etag: String, body: Vec<u8>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.insert(filepath.to_string(), (etag, body));
+    }
+}
+
+// Seconds since the epoch as an HTTP date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; // 1970-01-01 was a thursday
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = secs / 86400;
+    let time = secs % 86400;
+    // days -> civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+    )
+}
+
+// Guessing by the file extension, anything unknown is just bytes
+pub fn content_type_for(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "js" | "mjs" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
 // Just websocket parsing & stringfier
 pub struct WebSocketFrame;
 
+pub struct ParsedFrame {
+    pub fin: bool, // false when more fragments of the same message follow
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
 impl WebSocketFrame {
+    pub const OPCODE_CONTINUATION: u8 = 0x0;
+    pub const OPCODE_TEXT: u8 = 0x1;
+    pub const OPCODE_BINARY: u8 = 0x2;
+    pub const OPCODE_CLOSE: u8 = 0x8;
+    pub const OPCODE_PING: u8 = 0x9;
+    pub const OPCODE_PONG: u8 = 0xA;
 
     pub fn to_websocket(payload: Vec<u8>) -> Vec<u8> {
+        Self::frame(Self::OPCODE_TEXT, payload)
+    }
+
+    pub fn to_binary_frame(payload: Vec<u8>) -> Vec<u8> {
+        Self::frame(Self::OPCODE_BINARY, payload)
+    }
+
+    // Close/ping/pong frames. Their payload can't go over 125 bytes
+    pub fn control(opcode: u8, mut payload: Vec<u8>) -> Vec<u8> {
+        payload.truncate(125);
+        Self::frame(opcode, payload)
+    }
+
+    fn frame(opcode: u8, payload: Vec<u8>) -> Vec<u8> {
         let mut frame = Vec::new();
-        frame.push(0x81);
+        frame.push(0x80 | opcode);
         
         let payload_len = payload.len();
         
@@ -205,53 +606,152 @@ impl WebSocketFrame {
         frame
     }
 
-    pub fn parse(data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+    // Pops one frame out of `data`, payload already unmasked
+    pub fn parse(data: &mut Vec<u8>) -> Result<ParsedFrame, Error> {
+        let not_arrived = || Error::new(
+            std::io::ErrorKind::Interrupted,
+            "Data not fully arrived yet",
+        );
+        // every read below is checked first, the rest of the frame may
+        // still be on its way
         if data.len() < 2 {
-            return Result::Err(Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid websocket frame",                                                   
-            ));
-        }
-        if (data[0] & 0x01) == 0 {
-            return Result::Err(Error::new(
-                std::io::ErrorKind::ConnectionAborted,
-                "Can't handle multiframe payloads yet!!!",
-            ));
+            return Result::Err(not_arrived());
         }
+        let fin = (data[0] & 0x80) != 0;
+        let opcode = data[0] & 0x0F;
         let mut payload_start = 2;
         let masking_bit = data[1] >> 7;
-        let mut mask : u32 = 0xFFFF;
+        let mut mask : Option<[u8; 4]> = None; // clients always mask, servers never do
         let mut payload_len : usize = (data[1] & 0x7F).into();
         if payload_len == 126 {
             // gotta read next 2 bytes
-            payload_len = u16::from_be_bytes([data[2], data[3]]).into();
+            let Some(len_bytes) = data.get(2..4) else {
+                return Result::Err(not_arrived());
+            };
+            payload_len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]).into();
             payload_start += 2;
         } else if payload_len == 127 {
             // gotta read next 8 bytes
-            payload_len = u64::from_be_bytes(data[2..=9].try_into().unwrap()).try_into().unwrap();
+            let Some(len_bytes) = data.get(2..10) else {
+                return Result::Err(not_arrived());
+            };
+            payload_len = u64::from_be_bytes(len_bytes.try_into().unwrap()).try_into().map_err(|_| Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Websocket frame too big",
+            ))?;
             payload_start +=8;
         }
         if masking_bit == 1 {
-            mask = u32::from_be_bytes(data[payload_start..(payload_start+4)].try_into().unwrap());
+            let Some(mask_bytes) = data.get(payload_start..(payload_start+4)) else {
+                return Result::Err(not_arrived());
+            };
+            mask = Some(mask_bytes.try_into().unwrap());
             payload_start += 4;
         }
 
-        if data.len() < (payload_start + payload_len) {
+        let Some(frame_len) = payload_start.checked_add(payload_len) else {
             return Result::Err(Error::new(
-                std::io::ErrorKind::Interrupted,
-                "Data not fully arrived yet",
+                std::io::ErrorKind::InvalidData,
+                "Websocket frame too big",
             ));
+        };
+        if data.len() < frame_len {
+            return Result::Err(not_arrived());
         }
         
-        let mut payloadvec : Vec<u8> = data.drain(0..payload_start+payload_len).collect();
+        let mut payloadvec : Vec<u8> = data.drain(0..frame_len).collect();
         payloadvec.drain(0..payload_start);
 
-        if masking_bit > 0 {
-            payloadvec = payloadvec.iter().enumerate().map(
-                |(index, byte)| (byte) ^ (mask.to_be_bytes())[index % 4]
-            ).collect();
+        if let Some(mask) = mask {
+            for (index, byte) in payloadvec.iter_mut().enumerate() {
+                *byte ^= mask[index % 4];
+            }
         }
 
-        return Ok(payloadvec.to_vec());
+        Ok(ParsedFrame {
+            fin,
+            opcode,
+            payload: payloadvec,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_frames_have_the_binary_opcode_and_rfc_6455_lengths() {
+        // up to 125 the length fits in the second byte
+        let frame = WebSocketFrame::to_binary_frame(vec![7; 125]);
+        assert_eq!(frame[0], 0x82);
+        assert_eq!(frame[1], 125);
+        assert_eq!(frame.len(), 2 + 125);
+
+        // then 126 and a 16 bit length
+        let frame = WebSocketFrame::to_binary_frame(vec![7; 126]);
+        assert_eq!(frame[0], 0x82);
+        assert_eq!(frame[1], 126);
+        assert_eq!(frame[2..4], 126u16.to_be_bytes());
+        assert_eq!(frame.len(), 4 + 126);
+
+        // then 127 and a 64 bit length
+        let frame = WebSocketFrame::to_binary_frame(vec![7; 65536]);
+        assert_eq!(frame[0], 0x82);
+        assert_eq!(frame[1], 127);
+        assert_eq!(frame[2..10], 65536u64.to_be_bytes());
+        assert_eq!(frame.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn text_frames_keep_the_text_opcode() {
+        let frame = WebSocketFrame::to_websocket(b"{}".to_vec());
+        assert_eq!(frame, [0x81, 2, b'{', b'}']);
+    }
+
+    fn handshake_request(version: Option<&str>) -> HttpRequest {
+        let mut headers = HashMap::new();
+        headers.insert("upgrade".to_string(), "websocket".to_string());
+        headers.insert("sec-websocket-key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string());
+        if let Some(version) = version {
+            headers.insert("sec-websocket-version".to_string(), version.to_string());
+        }
+        HttpRequest {
+            method: HttpMethod::GET,
+            version: "HTTP/1.1".to_string(),
+            path: "/".to_string(),
+            query: HashMap::new(),
+            headers,
+            body: None,
+        }
+    }
+
+    #[test]
+    fn handshakes_need_websocket_version_13() {
+        for version in [None, Some("8"), Some("")] {
+            let response = HttpResponse::websocket_handshake(&handshake_request(version), &[]);
+            assert_eq!(response.status_code(), 426);
+            assert_eq!(response.headers.get("Sec-WebSocket-Version").map(String::as_str), Some("13"));
+        }
+
+        let response = HttpResponse::websocket_handshake(&handshake_request(Some("13")), &[]);
+        assert_eq!(response.status_code(), 101);
+        // the RFC 6455 example key
+        assert_eq!(response.headers.get("Sec-Websocket-Accept").map(String::as_str), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    }
+
+    #[test]
+    fn unmasks_the_rfc_6455_hello() {
+        // RFC 6455 section 5.7, a single-frame masked text message
+        let mut data = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = WebSocketFrame::parse(&mut data).unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, WebSocketFrame::OPCODE_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+        assert!(data.is_empty());
+
+        // and its unmasked twin, as a server would send it
+        let mut data = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert_eq!(WebSocketFrame::parse(&mut data).unwrap().payload, b"Hello");
     }
 }
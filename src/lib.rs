@@ -5,8 +5,8 @@ pub mod protocol;
 pub mod http;
 
 // Re-exporta tipos principais para facilitar o uso
-pub use game::{Direction, GameState, Position, Snake, Food};
-pub use protocol::{ClientGameMessage, ServerMessage};
+pub use game::{bot_direction, ActiveEffect, Effect, Direction, GameDelta, GameState, GameSummary, Position, Snake, SnakeDelta, SnakeSummary, SnakeConfig, Snakes, Food, FoodKind, SpeedRamp, TickOutcome, WallMode};
+pub use protocol::{ClientGameMessage, ClientMessage, ServerMessage};
 
 // Re-exporta GameServer diretamente
-pub use crate::gameserver::GameServer;
\ No newline at end of file
+pub use crate::gameserver::{ClientConnection, GameEventSender, GameServer, GameServerBuilder, GameServerConfig, NoopObserver, ServerObserver};
\ No newline at end of file
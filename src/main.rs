@@ -1,8 +1,42 @@
+use std::time::Duration;
+
 use snake_online::GameServer;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
-    let mut server = GameServer::new();
+    // RUST_LOG=debug (or snake_online=warn, etc) to pick what gets logged
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+    let mut builder = GameServer::builder();
+    if let Some(tick_ms) = std::env::var("APP_TICK_MS").ok().and_then(|ms| ms.parse::<u64>().ok()) {
+        builder = builder.tick_ms(tick_ms);
+    }
+    if let Some(secs) = std::env::var("APP_MAX_GAME_DURATION").ok().and_then(|secs| secs.parse::<u64>().ok()) {
+        builder = builder.max_game_duration(Duration::from_secs(secs));
+    }
+    if let Ok(path) = std::env::var("APP_HIGHSCORES_FILE") {
+        builder = builder.highscore_file(path);
+    }
+    if let Ok(path) = std::env::var("APP_STATIC_DIR") {
+        builder = builder.static_dir(path);
+    }
+    if let Ok(file) = std::env::var("APP_INDEX_FILE") {
+        builder = builder.index_file(&file);
+    }
+    if let Ok(origins) = std::env::var("APP_ALLOWED_ORIGINS") {
+        for origin in origins.split(',').map(str::trim).filter(|origin| !origin.is_empty()) {
+            builder = builder.allowed_origin(origin);
+        }
+    }
+    if let Some(max_connections) = std::env::var("APP_MAX_CONNECTIONS").ok().and_then(|max| max.parse::<usize>().ok()) {
+        builder = builder.max_connections(max_connections);
+    }
+    if std::env::var("APP_SCORE_SUBMISSION").is_ok_and(|enabled| enabled == "1" || enabled.eq_ignore_ascii_case("true")) {
+        builder = builder.score_submission(true);
+    }
+    let mut server = builder.build();
     server.run([
         std::env::var("APP_HOST").unwrap_or("0.0.0.0".to_string()),
         std::env::var("APP_PORT").unwrap_or("8080".to_string())
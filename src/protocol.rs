@@ -1,76 +1,156 @@
-use std::{cmp::min, collections::HashMap, io::ErrorKind, str::FromStr};
+use std::{collections::HashMap, fmt, io::ErrorKind, str::FromStr};
 
-use crate::{game::{Direction, GameState, JoinGame}, http::{HttpMethod, HttpRequest, WebSocketFrame}};
+use crate::{game::{Direction, GameDelta, GameState, GameSummary, JoinGame, Position, Size}, http::{HttpMethod, HttpRequest, ParsedFrame, WebSocketFrame}};
 use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+pub static MAX_HTTP_BUFFER_LEN : usize = 8192;
+static MAX_BOARD_ENTRIES : usize = 100; // high scores kept per board, way more than any top list shows
+// Bumped on every change to the messages that would break existing clients
+pub const PROTOCOL_VERSION : u32 = 1;
 
 #[derive(Debug)]
 pub enum ClientMessage {
     ClientGameMessage(ClientGameMessage),
     HttpRequest(HttpRequest),
-    Invalid,
+    Invalid(InvalidReason),
     Incomplete,
-    Disconnect
+    Disconnect,
+    Ping(Vec<u8>), // websocket control frame, not the json "ping" message
+    Pong,
+}
+
+// Why a chunk of bytes couldn't be turned into a ClientMessage
+#[derive(Debug)]
+pub enum InvalidReason {
+    MalformedHttp,
+    BodyTooLarge,
+    NonUtf8Payload,
+    BadJson(String),
+    BadFrame(String),
+    ReadError(String),
+}
+
+impl fmt::Display for InvalidReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidReason::MalformedHttp => write!(f, "malformed http request"),
+            InvalidReason::BodyTooLarge => write!(f, "http request body is too large"),
+            InvalidReason::NonUtf8Payload => write!(f, "websocket payload is not valid utf8"),
+            InvalidReason::BadJson(err) => write!(f, "bad json: {}", err),
+            InvalidReason::BadFrame(err) => write!(f, "bad websocket frame: {}", err),
+            InvalidReason::ReadError(err) => write!(f, "error reading socket: {}", err),
+        }
+    }
 }
 
-pub fn parse_client_message(payload: &mut Vec<u8>) -> ClientMessage {
-    match String::from_utf8(payload.to_vec()) {
+// `fragments` holds the pieces of a fragmented websocket message received so
+// far, it must outlive a single call (one per connection)
+pub fn parse_client_message(payload: &mut Vec<u8>, fragments: &mut Vec<u8>) -> ClientMessage {
+    // only the head of a http request is looked at, whatever comes after
+    // it (e.g. a pipelined request) stays in the buffer
+    let head_end = payload.windows(4).position(|window| window == b"\r\n\r\n");
+    match String::from_utf8(payload[..head_end.unwrap_or(payload.len())].to_vec()) {
         // If it is utf8 string, it probably is http request, not websocket frame
         Ok(string) => {
-                if let Ok(httpmethod) = HttpMethod::from_str(&string) {
-                if let Some((_, b)) = string.split_once(' ') {
-                    if let Some((path, b)) = b.split_once(' ') {
-                        if let Some((http_version, b)) = b.split_once("\r\n") {
-                            let mut ret = HttpRequest {
-                                method: httpmethod,
-                                version: http_version.to_string(),
-                                path: path.to_string(),
-                                headers: HashMap::new(),
-                            };
-                            let (headers, _) = b.split_once("\r\n\r\n").unwrap_or_default();
-                            for part in headers.split("\r\n") {
-                                if let Some((k,v)) = part.split_once(": ") {
-                                    ret.headers.insert(k.to_string(), v.to_string());
-                                }
+            if let Ok(httpmethod) = HttpMethod::from_str(&string) {
+                let Some(head_end) = head_end else {
+                    // headers not fully arrived yet
+                    return ClientMessage::Incomplete;
+                };
+                let (request_line, headers) = string.split_once("\r\n").unwrap_or((&string, ""));
+                if let Some((_, b)) = request_line.split_once(' ') {
+                    if let Some((path, http_version)) = b.split_once(' ') {
+                        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+                        let mut ret = HttpRequest {
+                            method: httpmethod,
+                            version: http_version.to_string(),
+                            path: path.to_string(),
+                            query: HttpRequest::parse_query(query),
+                            headers: HashMap::new(),
+                            body: None,
+                        };
+                        for part in headers.split("\r\n") {
+                            if let Some((k,v)) = part.split_once(": ") {
+                                ret.headers.insert(k.to_ascii_lowercase(), v.to_string());
+                            }
+                        }
+                        let body_start = head_end + 4;
+                        let mut request_end = body_start;
+                        if let Some(content_length) = ret.headers.get("content-length") {
+                            match content_length.trim().parse::<usize>() {
+                                Ok(len) if len > MAX_HTTP_BUFFER_LEN.saturating_sub(body_start) => {
+                                    payload.clear();
+                                    return ClientMessage::Invalid(InvalidReason::BodyTooLarge);
+                                },
+                                // body not fully arrived yet
+                                Ok(len) if payload.len() < body_start + len => return ClientMessage::Incomplete,
+                                Ok(len) => {
+                                    request_end += len;
+                                    ret.body = Some(payload[body_start..request_end].to_vec());
+                                },
+                                Err(_) => {
+                                    payload.clear();
+                                    return ClientMessage::Invalid(InvalidReason::MalformedHttp);
+                                },
                             }
-                            payload.clear();
-                            return ClientMessage::HttpRequest(ret);
                         }
+                        payload.drain(..request_end);
+                        return ClientMessage::HttpRequest(ret);
                     }
                 }
             }
             payload.clear();
-            return ClientMessage::Invalid;
+            ClientMessage::Invalid(InvalidReason::MalformedHttp)
         },
 
         // probably, websocket
         Err(_) => match WebSocketFrame::parse(payload) {
-            Ok(ws) => {
+            Ok(ParsedFrame { opcode: WebSocketFrame::OPCODE_CLOSE, .. }) => ClientMessage::Disconnect,
+            Ok(ParsedFrame { opcode: WebSocketFrame::OPCODE_PING, payload, .. }) => ClientMessage::Ping(payload),
+            Ok(ParsedFrame { opcode: WebSocketFrame::OPCODE_PONG, .. }) => ClientMessage::Pong,
+            Ok(ParsedFrame { fin: false, payload, .. }) => {
+                fragments.extend(payload);
+                if fragments.len() > MAX_HTTP_BUFFER_LEN {
+                    fragments.clear();
+                    ClientMessage::Invalid(InvalidReason::BadFrame("fragmented message is too big".to_string()))
+                } else {
+                    ClientMessage::Incomplete
+                }
+            },
+            Ok(ParsedFrame { payload, .. }) => {
+                let ws = if fragments.is_empty() {
+                    payload
+                } else {
+                    // last fragment, glue everything back together
+                    fragments.extend(payload);
+                    std::mem::take(fragments)
+                };
                 if let Ok(string) = String::from_utf8(ws) {
                     let result : Result<ClientGameMessage,_> = serde_json::from_str(&string);
                     match result {
                         Ok(msg) => {
                             ClientMessage::ClientGameMessage(msg)
                         },
-                        Err(_) => ClientMessage::Invalid
+                        Err(err) => ClientMessage::Invalid(InvalidReason::BadJson(err.to_string()))
                     }
                 } else {
                     // not valid utf8 websocket dataframe
-                    ClientMessage::Invalid
+                    ClientMessage::Invalid(InvalidReason::NonUtf8Payload)
                 }
             },
             Err(e) if e.kind() == ErrorKind::Interrupted => {
                 // data not fully arrived yet
                 // the only kind of error after trying to parse websocket frame
                 // that we dont clear the buffer
-                println!("Someone is sending websocket dataframes without nagle's alg");
+                debug!("Someone is sending websocket dataframes without nagle's alg");
                 ClientMessage::Incomplete
             },
             Err(e) => {
-                println!("{}", e);
                 // something wrong, this probably isnt a websocket frame,
                 // so lets reset buffer
                 payload.clear();
-                ClientMessage::Invalid
+                ClientMessage::Invalid(InvalidReason::BadFrame(e.to_string()))
             },
         },
     }
@@ -92,49 +172,195 @@ pub enum ClientGameMessage {
     Username { username: String},
     #[serde(rename = "ping")]
     Ping,
+    #[serde(rename = "leave_game")]
+    LeaveGame,
+    #[serde(rename = "resync")]
+    Resync, // asks for a full game_state after missing a game_delta
+    #[serde(rename = "list_games")]
+    ListGames,
+    #[serde(rename = "spectate")]
+    Spectate { game_id: String, summary: Option<bool> }, // watch a game without playing in it
+    #[serde(rename = "chat")]
+    Chat { text: String },
+    #[serde(rename = "resume")]
+    Resume { token: String }, // picks up where a dropped connection left off
+    #[serde(rename = "hello")]
+    Hello { version: u32 }, // optional, the protocol version the client speaks
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ClientGameMessage {
+    // The "type" it goes by on the wire
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ClientGameMessage::JoinGame(_) => "join_game",
+            ClientGameMessage::Input { .. } => "input",
+            ClientGameMessage::ResetGame => "reset_game",
+            ClientGameMessage::SetSpeed { .. } => "set_speed",
+            ClientGameMessage::Username { .. } => "username",
+            ClientGameMessage::Ping => "ping",
+            ClientGameMessage::LeaveGame => "leave_game",
+            ClientGameMessage::Resync => "resync",
+            ClientGameMessage::ListGames => "list_games",
+            ClientGameMessage::Spectate { .. } => "spectate",
+            ClientGameMessage::Chat { .. } => "chat",
+            ClientGameMessage::Resume { .. } => "resume",
+            ClientGameMessage::Hello { .. } => "hello",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HighScoreEntry {
     pub username: String,
     pub score: u32,
+    #[serde(default)]
+    pub won: bool, // filled the board instead of crashing, or outlived everyone in versus
+}
+
+// A game as seen from the lobby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub game_id: String,
+    pub players: usize,
+    pub max_players: Option<usize>,
+    pub size: Size,
+    pub game_over: bool,
+}
+
+// Scores are kept on the board of the kind of game they were made in (see
+// GameState::board_key), scores from different board sizes or modes aren't
+// comparable. The global board is the best of all of them, plus the scores
+// that came from no game in particular
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HighScoreBoards {
+    pub global: Vec<HighScoreEntry>, // only the ones with no board of their own
+    pub boards: HashMap<String, Vec<HighScoreEntry>>,
+}
+impl HighScoreBoards {
+    // False if it didn't make it onto the board
+    pub fn push(&mut self, board: Option<&str>, entry: HighScoreEntry) -> bool {
+        let entries = match board {
+            Some(board) => self.boards.entry(board.to_string()).or_default(),
+            None => &mut self.global,
+        };
+        match entries.iter_mut().find(|old| old.username == entry.username) {
+            Some(old) if old.score >= entry.score => return false,
+            Some(old) => *old = entry.clone(),
+            None => entries.push(entry.clone()),
+        }
+        *entries = HighScores::top(entries, MAX_BOARD_ENTRIES);
+        entries.iter().any(|kept| kept.username == entry.username)
+    }
+
+    // The global board for None
+    pub fn board(&self, board: Option<&str>) -> Vec<HighScoreEntry> {
+        match board {
+            Some(board) => self.boards.get(board).cloned().unwrap_or_default(),
+            None => {
+                let all: Vec<HighScoreEntry> = self.global.iter().chain(self.boards.values().flatten()).cloned().collect();
+                HighScores::top(&all, MAX_BOARD_ENTRIES)
+            },
+        }
+    }
+
+    // Files from before boards were trimmed kept everything, twice
+    pub fn trimmed(mut self) -> Self {
+        let boarded: Vec<&HighScoreEntry> = self.boards.values().flatten().collect();
+        self.global.retain(|entry| !boarded.contains(&entry));
+        self.global = HighScores::top(&self.global, MAX_BOARD_ENTRIES);
+        for entries in self.boards.values_mut() {
+            *entries = HighScores::top(entries, MAX_BOARD_ENTRIES);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.global.len() + self.boards.values().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HighScores {
-    pub highscores: HashMap<String, HighScoreEntry>
+    pub highscores: HashMap<String, HighScoreEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board: Option<String>, // None for the global board
 }
 impl HighScores {
-    pub fn from_vec(value: &mut Vec<HighScoreEntry>) -> Self {
-        let mut ret = HashMap::new();
-        value.sort_by(
-            |a, b| {b.score.cmp(&a.score)}
-        );
-        for i in 0..min(10, value.len()) {
-            ret.insert(
-                format!("{}", i+1),
-                value.get(i).unwrap().clone(),
-            );
-        }
+    // Top 10, counting only each username's best score
+    pub fn from_vec(value: &[HighScoreEntry]) -> Self {
         HighScores{
-            highscores: ret
+            highscores: Self::top(value, 10)
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| (format!("{}", i+1), entry))
+                .collect(),
+            board: None,
+        }
+    }
+
+    pub fn from_board(boards: &HighScoreBoards, board: Option<&str>) -> Self {
+        HighScores {
+            board: board.map(str::to_string),
+            ..Self::from_vec(&boards.board(board))
         }
     }
+
+    // Best first, one entry per username
+    pub fn top(value: &[HighScoreEntry], limit: usize) -> Vec<HighScoreEntry> {
+        let mut best : HashMap<&str, &HighScoreEntry> = HashMap::new();
+        for entry in value {
+            best.entry(entry.username.as_str())
+                .and_modify(|old| if entry.score > old.score { *old = entry })
+                .or_insert(entry);
+        }
+        let mut entries : Vec<&HighScoreEntry> = best.into_values().collect();
+        entries.sort_by(
+            |a, b| {b.score.cmp(&a.score).then_with(|| a.username.cmp(&b.username))}
+        );
+        entries.truncate(limit);
+        entries.into_iter().cloned().collect()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
     #[serde(rename = "game_state")]
-    GameState(GameState),
+    GameState(Box<GameState>),
     #[serde(rename = "error")]
     Error { message: String },
     #[serde(rename = "pong")]
     Pong,
     #[serde(rename = "connected")]
-    Connected { client_id: String },
+    Connected { client_id: String, session_token: String, protocol_version: u32 }, // the token is what `resume` takes
     #[serde(rename = "highscores")]
     HighScores (HighScores),
+    #[serde(rename = "left_game")]
+    LeftGame,
+    #[serde(rename = "game_delta")]
+    GameDelta(GameDelta),
+    #[serde(rename = "game_list")]
+    GameList { games: Vec<RoomSummary> },
+    #[serde(rename = "player_joined")]
+    PlayerJoined { client_id: String, username: Option<String> },
+    #[serde(rename = "player_left")]
+    PlayerLeft { client_id: String },
+    #[serde(rename = "food_eaten")]
+    FoodEaten { position: Position, new_score: i32 },
+    #[serde(rename = "chat")]
+    Chat { username: String, text: String }, // username is the client id for anonymous players
+    #[serde(rename = "game_reset")]
+    GameReset { by: String }, // client id that reset it, the fresh game_state follows
+    #[serde(rename = "owner_changed")]
+    OwnerChanged { client_id: String },
+    #[serde(rename = "game_over")]
+    GameOver { winner: Option<String> }, // versus games, winner is a snake (client) id
+    #[serde(rename = "game_summary")]
+    GameSummary(GameSummary), // instead of game_state, for those who asked for summaries
 }
 
 impl ServerMessage {
@@ -145,10 +371,73 @@ impl ServerMessage {
     }
 
     pub fn game_state(state: GameState) -> Self {
-        ServerMessage::GameState(state)
+        ServerMessage::GameState(Box::new(state))
     }
 
-    pub fn connected(client_id: String) -> Self {
-        ServerMessage::Connected { client_id }
+    // Same json as a ServerMessage::GameState, without having to own (clone) the state
+    pub fn game_state_json(state: &GameState) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        #[serde(tag = "type")]
+        enum BorrowedMessage<'a> {
+            #[serde(rename = "game_state")]
+            GameState(&'a GameState),
+        }
+        serde_json::to_string(&BorrowedMessage::GameState(state))
+    }
+
+    pub fn game_delta_json(delta: &GameDelta) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        #[serde(tag = "type")]
+        enum BorrowedMessage<'a> {
+            #[serde(rename = "game_delta")]
+            GameDelta(&'a GameDelta),
+        }
+        serde_json::to_string(&BorrowedMessage::GameDelta(delta))
+    }
+
+    pub fn connected(client_id: String, session_token: String) -> Self {
+        ServerMessage::Connected { client_id, session_token, protocol_version: PROTOCOL_VERSION }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masked_pong_frames_are_pongs() {
+        // what a browser sends back for our ping: fin + pong, masked,
+        // with the ping's (here 2 byte) payload
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut payload = vec![0x8A, 0x80 | 2];
+        payload.extend(mask);
+        payload.extend([b'h' ^ mask[0], b'i' ^ mask[1]]);
+        let mut fragments = Vec::new();
+
+        assert!(matches!(parse_client_message(&mut payload, &mut fragments), ClientMessage::Pong));
+        assert!(payload.is_empty());
+        assert!(fragments.is_empty());
+    }
+
+    #[test]
+    fn high_score_boards_keep_each_users_best_once() {
+        let entry = |username: &str, score: u32| HighScoreEntry { username: username.to_string(), score, won: false };
+        let mut boards = HighScoreBoards::default();
+        assert!(boards.push(Some("20x20"), entry("ana", 10)));
+        assert!(!boards.push(Some("20x20"), entry("ana", 5)));
+        assert!(boards.push(Some("20x20"), entry("ana", 30)));
+        assert!(boards.push(None, entry("bia", 20)));
+        // stored once, the global board is worked out from the others
+        assert_eq!(boards.len(), 2);
+        assert_eq!(boards.board(Some("20x20")), vec![entry("ana", 30)]);
+        assert_eq!(boards.board(None), vec![entry("ana", 30), entry("bia", 20)]);
+
+        for i in 0..MAX_BOARD_ENTRIES as u32 {
+            boards.push(Some("20x20"), entry(&format!("player{}", i), 100 + i));
+        }
+        assert_eq!(boards.board(Some("20x20")).len(), MAX_BOARD_ENTRIES);
+        // ana fell off the bottom, and can't get in without beating the last one
+        assert!(!boards.board(Some("20x20")).contains(&entry("ana", 30)));
+        assert!(!boards.push(Some("20x20"), entry("ana", 40)));
     }
 }